@@ -0,0 +1,360 @@
+use std::ffi::{OsStr, OsString};
+use std::fs::Permissions;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, ensure};
+use diffy::Patch;
+
+use crate::changes::{ChangeKind, Changes, Content, FileType, read_mode};
+use crate::diff_patch::MetaHunk;
+
+/// A file or symlink with its content, which is the target for symlinks.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub file_type: FileType,
+    pub content: Vec<u8>,
+    pub mode: u32,
+}
+
+/// A change before and after applying the selected hunks. Paths are relative to the base dirs.
+pub struct Outcome {
+    /// Where the change currently lives in the modified dir.
+    pub current: PathBuf,
+    pub original: Option<(PathBuf, Entry)>,
+    pub result: Option<(PathBuf, Entry)>,
+    /// Whether `original` still exists next to `result`, i.e. the file was copied.
+    pub copied: bool,
+}
+
+impl Outcome {
+    pub fn is_unchanged(&self) -> bool {
+        self.original == self.result
+    }
+}
+
+#[test]
+fn check_partially_removed() {
+    let dir = std::env::temp_dir().join(format!("diffpatch-removed-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("original")).unwrap();
+    std::fs::create_dir_all(dir.join("modified")).unwrap();
+    std::fs::write(dir.join("original/gone"), "1\n2\n3\n").unwrap();
+    let (original, modified) = (dir.join("original"), dir.join("modified"));
+    let changes = Changes::detect(&original, &modified, &modified, &[]).unwrap();
+    let change = changes.iter().next().unwrap();
+    let content = (
+        Content::Text("1\n2\n3\n".into()),
+        Content::Text(String::new()),
+    );
+
+    // the only hunk, with just its first line selected
+    let patch = diffy::create_patch("1\n2\n3\n", "2\n3\n");
+    let partial = outcome(&changes, change, &content, &[], &patch, (&[], &[true])).unwrap();
+    let (_, result) = partial.result.unwrap();
+    assert_eq!(result.content, b"2\n3\n");
+
+    let patch = diffy::create_patch("1\n2\n3\n", "");
+    let removed = outcome(&changes, change, &content, &[], &patch, (&[], &[true])).unwrap();
+    assert!(removed.result.is_none());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+/// Computes what a change ends up as. Rejected hunks must already be removed from `patch`.
+pub fn outcome(
+    changes: &Changes,
+    change: &ChangeKind,
+    (original, modified): &(Content, Content),
+    metas: &[MetaHunk],
+    patch: &Patch<str>,
+    (meta_resolution, hunk_resolution): (&[bool], &[bool]),
+) -> Result<Outcome> {
+    let applied = match (original, modified) {
+        (Content::Text(original), Content::Text(_)) => diffy::apply(original, patch)
+            .with_context(|| format!("Could not apply patch\n{}", patch))?
+            .into_bytes(),
+        _ => match hunk_resolution.iter().all(|&resolution| resolution) {
+            true => modified.as_bytes().to_vec(),
+            false => original.as_bytes().to_vec(),
+        },
+    };
+    let any_accepted = hunk_resolution.iter().any(|&resolution| resolution);
+    let all_accepted = hunk_resolution.iter().all(|&resolution| resolution);
+
+    let original_path = changes.original_path(change.original());
+    let file = |content: Vec<u8>, mode| Entry {
+        file_type: FileType::File,
+        content,
+        mode,
+    };
+    let original_file = || -> Result<_> {
+        let entry = file(original.as_bytes().to_vec(), read_mode(&original_path)?);
+        Ok((change.original().to_owned(), entry))
+    };
+
+    let mut copied = false;
+    let (original_entry, result) = match change {
+        ChangeKind::Modified(_) | ChangeKind::Renamed(..) | ChangeKind::Copied(..) => {
+            let mut path = change.modified().to_owned();
+            let mut mode = changes.modified_mode(change.modified())?;
+            let mut kept = true;
+            for (meta, &resolution) in metas.iter().zip(meta_resolution) {
+                match *meta {
+                    MetaHunk::Mode(original, modified) => {
+                        mode = if resolution { modified } else { original }
+                    }
+                    MetaHunk::Rename(ref from, _) if resolution == false => path = from.clone(),
+                    MetaHunk::Copy(..) => (copied, kept) = (true, resolution),
+                    MetaHunk::Rename(..) => {}
+                }
+            }
+            match kept {
+                true => (Some(original_file()?), Some((path, file(applied, mode)))),
+                false => (None, None),
+            }
+        }
+        ChangeKind::Removed(path) => {
+            // unless every line was removed, the file stays with only the selected lines removed
+            let result = match any_accepted && applied.is_empty() {
+                true => None,
+                false => Some((path.clone(), file(applied, read_mode(&original_path)?))),
+            };
+            (Some(original_file()?), result)
+        }
+        ChangeKind::Added(path) => {
+            // if any hunk was accepted, the file is added with only the selected lines
+            let result = match any_accepted {
+                true => Some((path.clone(), file(applied, changes.modified_mode(path)?))),
+                false => None,
+            };
+            (None, result)
+        }
+        ChangeKind::Symlink(path, original_type, modified_type) => {
+            let original_mode = original_type.map(|_| read_mode(&original_path));
+            let modified_mode = modified_type.map(|_| changes.modified_mode(path));
+            let (original_mode, modified_mode) =
+                (original_mode.transpose()?, modified_mode.transpose()?);
+            let entry = |file_type: Option<FileType>, mode: Option<u32>, content| {
+                let entry = Entry {
+                    file_type: file_type?,
+                    content,
+                    mode: mode?,
+                };
+                Some((path.clone(), entry))
+            };
+            let original_bytes = original.as_bytes().to_vec();
+            let original_entry = entry(*original_type, original_mode, original_bytes.clone());
+            // a file can't turn into a symlink to some of its lines, so type changes are all or
+            // nothing, like binary files
+            let result = match (original_type == modified_type, all_accepted) {
+                (true, _) => entry(*modified_type, modified_mode, applied),
+                (false, true) => entry(*modified_type, modified_mode, modified.as_bytes().to_vec()),
+                (false, false) => entry(*original_type, original_mode, original_bytes),
+            };
+            (original_entry, result)
+        }
+    };
+
+    Ok(Outcome {
+        current: change.modified().to_owned(),
+        original: original_entry,
+        result,
+        copied,
+    })
+}
+
+#[test]
+fn check_write_rollback() {
+    let dir = std::env::temp_dir().join(format!("diffpatch-rollback-{}", std::process::id()));
+    let (original, modified) = (dir.join("original"), dir.join("modified"));
+    for dir in [&original, &modified] {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("a"), "a\n").unwrap();
+    }
+    // a file where a dir is needed makes the last write fail
+    std::fs::write(modified.join("blocked"), "").unwrap();
+    let changes = Changes::detect(&original, &modified, &modified, &[]).unwrap();
+
+    let entry = |content: &str| Entry {
+        file_type: FileType::File,
+        content: content.as_bytes().to_vec(),
+        mode: 0o100644,
+    };
+    let outcome = |path: &str, content| Outcome {
+        current: PathBuf::from(path),
+        original: None,
+        result: Some((PathBuf::from(path), entry(content))),
+        copied: false,
+    };
+    let outcomes = [
+        outcome("a", "b\n"),
+        outcome("new/dir/a", "a\n"),
+        outcome("blocked/a", "a\n"),
+    ];
+    assert!(write_outcomes(&changes, &outcomes).is_err());
+    assert_eq!(std::fs::read(modified.join("a")).unwrap(), b"a\n");
+    assert!(!modified.join("new").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+/// Makes the modified dir match all outcomes. If writing any of them fails, every file touched
+/// so far is restored.
+pub fn write_outcomes(changes: &Changes, outcomes: &[Outcome]) -> Result<()> {
+    if changes.output_is_original {
+        for outcome in outcomes {
+            let (path, original) = match &outcome.original {
+                Some((path, entry)) => (path, Some(entry)),
+                None => (&outcome.current, None),
+            };
+            let path = changes.output_path(path);
+            let current = read_entry(&path)
+                .with_context(|| format!("could not read '{}'", path.display()))?;
+            // only the type and content count, as git may not see the actual mode
+            let unchanged = match (&current, original) {
+                (Some(current), Some(original)) => {
+                    current.file_type == original.file_type && current.content == original.content
+                }
+                (current, original) => current.is_none() && original.is_none(),
+            };
+            ensure!(
+                unchanged,
+                "'{}' was changed in the meantime, no changes were written",
+                path.display()
+            );
+        }
+    }
+
+    let mut backups = Vec::new();
+    // an output holding the original side already matches unchanged outcomes
+    let outcomes =
+        (outcomes.iter()).filter(|outcome| !(changes.output_is_original && outcome.is_unchanged()));
+    for outcome in outcomes {
+        let Err(err) = write_outcome(changes, outcome, &mut backups) else {
+            continue;
+        };
+
+        // a path can be touched more than once, so restore the oldest state last
+        let mut failed = Vec::new();
+        for (path, entry) in backups.iter().rev() {
+            let restored = match entry {
+                Some(entry) => write_entry(path, entry),
+                None if path.symlink_metadata().is_ok_and(|meta| meta.is_dir()) => {
+                    std::fs::remove_dir(path)
+                }
+                None => remove_entry(path),
+            };
+            if restored.is_err() {
+                failed.push(format!("'{}'", path.display()));
+            }
+        }
+        return Err(match failed.is_empty() {
+            true => err.wrap_err("no changes were written, all files have been restored"),
+            false => err.wrap_err(format!("could not restore {}", failed.join(", "))),
+        });
+    }
+
+    Ok(())
+}
+
+/// Makes the output dir match the outcome of a change, recording the previous state of every
+/// path it touches in `backups`.
+fn write_outcome(
+    changes: &Changes,
+    outcome: &Outcome,
+    backups: &mut Vec<(PathBuf, Option<Entry>)>,
+) -> Result<()> {
+    let backup = |path: &Path| -> Result<_> {
+        let entry =
+            read_entry(path).with_context(|| format!("could not read '{}'", path.display()))?;
+        Ok((path.to_owned(), entry))
+    };
+    let path = outcome
+        .result
+        .as_ref()
+        .map(|(path, _)| changes.output_path(path));
+
+    // when patching a dir in place, the source of a rename lives in the output dir as well
+    let source = (outcome.original.as_ref()).filter(|_| !outcome.copied);
+    let stale = std::iter::once(&outcome.current).chain(source.map(|(path, _)| path));
+    for stale in stale.map(|stale| changes.output_path(stale)) {
+        if path.as_ref() != Some(&stale) && stale.symlink_metadata().is_ok() {
+            backups.push(backup(&stale)?);
+            remove_entry(&stale)
+                .with_context(|| format!("could not remove '{}'", stale.display()))?;
+        }
+    }
+    if let (Some(path), Some((_, entry))) = (path, &outcome.result) {
+        // rewriting an unchanged file would only give it a new inode
+        if read_entry(&path).is_ok_and(|current| current.as_ref() == Some(entry)) {
+            return Ok(());
+        }
+        // dirs created for the result are recorded as empty, to be removed again after it
+        let created = path.ancestors().skip(1);
+        let created = created
+            .take_while(|dir| !dir.as_os_str().is_empty() && dir.symlink_metadata().is_err());
+        let created: Vec<_> = created.map(|dir| (dir.to_owned(), None)).collect();
+        backups.extend(created.into_iter().rev());
+        backups.push(backup(&path)?);
+        write_entry(&path, entry)
+            .with_context(|| format!("could not write '{}'", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Reads a file or symlink, or `None` if nothing exists at `path`.
+pub fn read_entry(path: &Path) -> std::io::Result<Option<Entry>> {
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let (file_type, content) = match metadata.is_symlink() {
+        true => (
+            FileType::Symlink,
+            std::fs::read_link(path)?.into_os_string().into_vec(),
+        ),
+        false => (FileType::File, std::fs::read(path)?),
+    };
+    Ok(Some(Entry {
+        file_type,
+        content,
+        mode: metadata.permissions().mode(),
+    }))
+}
+
+fn remove_entry(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Writes `entry` next to `path` first and then renames it into place, so that `path` is
+/// never left half-written.
+pub fn write_entry(path: &Path, entry: &Entry) -> std::io::Result<()> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut temp_name = OsString::from(".");
+    temp_name.push(path.file_name().unwrap_or_default());
+    temp_name.push(".diffpatch-tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    remove_entry(&temp_path)?;
+    let written = match entry.file_type {
+        FileType::File => std::fs::write(&temp_path, &entry.content).and_then(|()| {
+            std::fs::set_permissions(&temp_path, Permissions::from_mode(entry.mode))
+        }),
+        FileType::Symlink => {
+            std::os::unix::fs::symlink(OsStr::from_bytes(&entry.content), &temp_path)
+        }
+    };
+    let renamed = written.and_then(|()| std::fs::rename(&temp_path, path));
+    if renamed.is_err() {
+        let _ = remove_entry(&temp_path);
+    }
+    renamed
+}
@@ -0,0 +1,189 @@
+use color_eyre::Result;
+use color_eyre::eyre::{ContextCompat, WrapErr, bail, eyre};
+use std::path::{Component, PathBuf};
+use std::str::FromStr;
+
+use crate::config::{Interface, Mode, Options};
+
+pub const USAGE: &str = "\
+Usage: diffpatch [OPTIONS] <LEFT> <RIGHT> [OUTPUT] [-- <PATHS>...]
+       diffpatch [OPTIONS] --patch <FILE> <TARGET> [-- <PATHS>...]
+       diffpatch [OPTIONS] git <add|reset|checkout|restore> [PATHS...]
+
+Interactively selects which changes from LEFT to RIGHT end up in RIGHT,
+or in OUTPUT if it is given as a copy of RIGHT,
+or which hunks of a patch FILE are applied to TARGET.
+Only changes below the given PATHS are shown.
+
+The git commands work like their --patch variants: add stages changes,
+reset unstages them and checkout discards them from the worktree.
+restore is checkout, or reset with --staged.
+
+Options:
+  -U, --context <N>          Number of context lines around each hunk
+      --interface <NAME>     One of direct, fullscreen, inline-clear
+  -R, --reverse              Show the changes from RIGHT to LEFT
+      --no-reverse           Show the changes from LEFT to RIGHT, even when
+                             the jj or git command would show them reversed
+      --no-immediate         Wait for enter after each command
+      --no-confirm           Don't ask before writing the changes
+      --no-color             Disable colored output
+      --editor <COMMAND>     Editor used to edit hunks
+      --mode <COMMAND>       The jj command diffpatch is used by, one of
+                             commit, split, squash, restore, diffedit
+      --output-patch <FILE>  Write the selected changes as a patch instead, - for stdout
+      --dry-run              Same as --output-patch -
+      --patch <FILE>         Select hunks from a patch instead of diffing two paths
+      --staged, --cached     Restore the index instead of the worktree with git restore
+  -h, --help                 Print this help
+  -V, --version              Print the version
+";
+
+/// The parsed command line. Options given here take precedence over config files and the
+/// environment, see [`Args::apply`].
+#[derive(Default)]
+pub struct Args {
+    pub paths: Vec<PathBuf>,
+    /// Only changes below these paths are shown.
+    pub filters: Vec<PathBuf>,
+    pub patch: Option<PathBuf>,
+    pub staged: bool,
+    /// `-R` or `--no-reverse`, which override the direction given by the mode.
+    pub reversed: Option<bool>,
+    pub help: bool,
+    pub version: bool,
+
+    context_len: Option<usize>,
+    interface: Option<Interface>,
+    no_immediate: bool,
+    no_confirm: bool,
+    no_color: bool,
+    editor: Option<String>,
+    mode: Option<Mode>,
+    output_patch: Option<PathBuf>,
+}
+
+#[test]
+fn check_parse_args() {
+    let args = [
+        "-U1",
+        "--interface=fullscreen",
+        "-R",
+        "a",
+        "--patch",
+        "p",
+        "--",
+        "./src/",
+        "-R",
+    ];
+    let args = Args::parse(args.into_iter().map(String::from)).unwrap();
+    assert_eq!(args.context_len, Some(1));
+    assert!(matches!(args.interface, Some(Interface::Fullscreen)));
+    assert_eq!(args.reversed, Some(true));
+    assert_eq!(args.paths, [PathBuf::from("a")]);
+    assert_eq!(args.patch, Some(PathBuf::from("p")));
+    assert_eq!(args.filters, [PathBuf::from("src"), PathBuf::from("-R")]);
+
+    let args = Args::parse(["-R", "--no-reverse"].into_iter().map(String::from)).unwrap();
+    assert_eq!(args.reversed, Some(false));
+
+    let invalid = [
+        "--context=-1",
+        "--reverse=yes",
+        "--interface",
+        "--unknown",
+        "-é",
+        "-éx",
+    ];
+    for invalid in invalid {
+        assert!(Args::parse([invalid.to_owned()].into_iter()).is_err());
+    }
+}
+impl Args {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Args> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            // values can be given as `--flag value`, `--flag=value` or `-Xvalue`
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value)),
+                _ if arg.starts_with('-') && !arg.starts_with("--") => {
+                    match arg.char_indices().nth(2) {
+                        Some((i, _)) => (&arg[..i], Some(&arg[i..])),
+                        None => (arg.as_str(), None),
+                    }
+                }
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || {
+                inline_value
+                    .map(str::to_owned)
+                    .or_else(|| args.next())
+                    .with_context(|| format!("missing value after {flag}"))
+            };
+
+            match flag {
+                "-h" | "--help" if inline_value.is_none() => parsed.help = true,
+                "-V" | "--version" if inline_value.is_none() => parsed.version = true,
+                "-U" | "--context" => parsed.context_len = Some(parse_value(flag, &value()?)?),
+                "--interface" => parsed.interface = Some(parse_value(flag, &value()?)?),
+                "-R" | "--reverse" if inline_value.is_none() => parsed.reversed = Some(true),
+                "--no-reverse" if inline_value.is_none() => parsed.reversed = Some(false),
+                "--no-immediate" if inline_value.is_none() => parsed.no_immediate = true,
+                "--no-confirm" if inline_value.is_none() => parsed.no_confirm = true,
+                "--no-color" if inline_value.is_none() => parsed.no_color = true,
+                "--editor" => parsed.editor = Some(value()?),
+                "--mode" => parsed.mode = Some(parse_value(flag, &value()?)?),
+                "--output-patch" => parsed.output_patch = Some(PathBuf::from(value()?)),
+                "--dry-run" if inline_value.is_none() => {
+                    parsed.output_patch = Some(PathBuf::from("-"))
+                }
+                "--patch" => parsed.patch = Some(PathBuf::from(value()?)),
+                "--staged" | "--cached" if inline_value.is_none() => parsed.staged = true,
+                "--" => {
+                    let filters = args.by_ref().map(|filter| {
+                        let filter = PathBuf::from(filter);
+                        // `./src/` should match `src/main.rs`
+                        (filter.components())
+                            .filter(|component| *component != Component::CurDir)
+                            .collect()
+                    });
+                    parsed.filters.extend(filters);
+                }
+                _ if arg.starts_with('-') && arg != "-" => bail!("unknown option '{arg}'"),
+                _ => parsed.paths.push(PathBuf::from(arg)),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Overrides the options which were given on the command line.
+    pub fn apply(&mut self, options: &mut Options) {
+        if let Some(context_len) = self.context_len {
+            options.context_len = context_len;
+        }
+        if let Some(interface) = self.interface.take() {
+            options.interface = interface;
+        }
+        options.immediate_command &= !self.no_immediate;
+        options.confirm &= !self.no_confirm;
+        options.color &= !self.no_color;
+        if let Some(editor) = self.editor.take() {
+            options.editor = Some(editor);
+        }
+        if let Some(mode) = self.mode {
+            options.mode = Some(mode);
+        }
+        if let Some(output_patch) = self.output_patch.take() {
+            options.output_patch = Some(output_patch);
+        }
+    }
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .parse()
+        .wrap_err_with(|| eyre!("{flag}={value} could not be parsed"))
+}
@@ -1,25 +1,186 @@
 use color_eyre::Result;
-use color_eyre::eyre::{ensure, eyre};
-use std::collections::BTreeSet;
+use color_eyre::eyre::{Context, ensure, eyre};
+use diffy::Patch;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
+use crate::{patch_file, renames};
+
 pub struct Changes {
     base_dir_original: PathBuf,
     base_dir_modified: PathBuf,
+    /// Where the selected changes are written, usually the modified dir.
+    base_dir_output: PathBuf,
+    /// Modified contents and modes which only exist in memory, e.g. when applying a patch file.
+    patched: HashMap<PathBuf, (Content, u32)>,
 
     pub changes: Vec<ChangeKind>,
+    /// The `JJ-INSTRUCTIONS` jj leaves in the output dir, e.g. which revision is being split.
+    pub instructions: Option<String>,
+    /// Whether the output dir is where the original side was copied from, like the worktree for
+    /// `git checkout`, which must not have changed when the changes are written.
+    pub output_is_original: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileType {
+    File,
+    Symlink,
 }
 
 pub enum ChangeKind {
     Modified(PathBuf),
     Removed(PathBuf),
     Added(PathBuf),
+    /// A symlink on at least one side. The link target is diffed like file contents.
+    Symlink(PathBuf, Option<FileType>, Option<FileType>),
+    /// A file moved from the first path to the second, possibly with modifications.
+    Renamed(PathBuf, PathBuf),
+    /// A file added at the second path as a copy of the first, possibly with modifications.
+    Copied(PathBuf, PathBuf),
 }
 
 impl Changes {
-    pub fn detect(original_dir: &Path, modified_dir: &Path) -> Result<Self> {
-        read_changes(original_dir, modified_dir)
+    /// Detects the changes from `original_dir` to `modified_dir`, which are written to `output_dir`.
+    /// jj passes a separate output dir with a copy of the modified dir for `$output`.
+    /// If `filters` are given, only the paths below them are read.
+    pub fn detect(
+        original_dir: &Path,
+        modified_dir: &Path,
+        output_dir: &Path,
+        filters: &[PathBuf],
+    ) -> Result<Self> {
+        let mut changes = read_changes(original_dir, modified_dir, filters)?;
+        changes.base_dir_output = resolve_file(output_dir)?;
+        changes.instructions = read_instructions(output_dir);
+        changes.skip_unchanged()?;
+        renames::detect_renames(&mut changes)?;
+        Ok(changes)
+    }
+
+    /// Reads the changes of a unified diff against `target_dir`, which serves as both sides.
+    pub fn from_patch(patch_path: &Path, target_dir: &Path) -> Result<Self> {
+        ensure!(
+            target_dir.is_dir(),
+            "{}: no such directory",
+            target_dir.display()
+        );
+        let input = std::fs::read_to_string(patch_path)
+            .with_context(|| format!("failed to read '{}'", patch_path.display()))?;
+        let files = patch_file::split_files(&input)
+            .with_context(|| format!("failed to parse '{}'", patch_path.display()))?;
+
+        let mut changes = Changes {
+            base_dir_original: target_dir.to_owned(),
+            base_dir_modified: target_dir.to_owned(),
+            base_dir_output: target_dir.to_owned(),
+            patched: HashMap::new(),
+            changes: Vec::new(),
+            instructions: None,
+            output_is_original: false,
+        };
+        for file in files {
+            let name = file.modified.as_ref().or(file.original.as_ref());
+            let name = name.map_or_else(String::new, |name| name.display().to_string());
+            ensure!(!file.binary, "'{name}': binary patches are not supported");
+            let patch = Patch::from_str(&file.hunks)
+                .with_context(|| format!("failed to parse the hunks of '{name}'"))?;
+
+            let original = match &file.original {
+                Some(path) => {
+                    let full_path = changes.original_path(path);
+                    let content = read_content(full_path.clone())
+                        .with_context(|| format!("failed to read '{}'", full_path.display()))?;
+                    Some((path, content, read_mode(&full_path)?))
+                }
+                None => None,
+            };
+            let original_text = match &original {
+                Some((_, Content::Binary(_), _)) if !patch.hunks().is_empty() => {
+                    return Err(eyre!("'{name}': cannot patch a binary file"));
+                }
+                Some((_, content, _)) => content.text(),
+                None => "",
+            };
+            let modified = diffy::apply(original_text, &patch)
+                .with_context(|| format!("'{name}': patch does not apply"))?;
+
+            let file_type = |mode: u32| match mode & 0o170000 == 0o120000 {
+                true => FileType::Symlink,
+                false => FileType::File,
+            };
+            let change = match (original, file.modified) {
+                (None, None) => continue,
+                (None, Some(path)) => {
+                    let mode = file.new_mode.unwrap_or(0o100644);
+                    // git shows a file turning into a symlink as a removal followed by an addition
+                    let removed = changes.changes.iter().position(|change| match change {
+                        ChangeKind::Removed(removed) | ChangeKind::Symlink(removed, _, None) => {
+                            *removed == path
+                        }
+                        _ => false,
+                    });
+                    let exists = changes.original_path(&path).symlink_metadata().is_ok();
+                    ensure!(
+                        removed.is_some() || !exists,
+                        "'{name}': already exists in the target"
+                    );
+                    let original_type =
+                        removed.and_then(|index| match changes.changes.remove(index) {
+                            ChangeKind::Symlink(_, original_type, _) => original_type,
+                            _ => Some(FileType::File),
+                        });
+                    let change =
+                        ChangeKind::new(path.clone(), original_type, Some(file_type(mode)));
+                    changes
+                        .patched
+                        .insert(path, (Content::Text(modified), mode));
+                    change
+                }
+                (Some((path, _, mode)), None) => {
+                    ChangeKind::new(path.clone(), Some(file_type(mode)), None)
+                }
+                (Some((from, content, original_mode)), Some(to)) => {
+                    let mode = file.new_mode.unwrap_or(original_mode);
+                    let exists = changes.original_path(&to).symlink_metadata().is_ok();
+                    ensure!(
+                        *from == to || !exists,
+                        "'{name}': already exists in the target"
+                    );
+                    if *from == to && content.text() == modified && mode == original_mode {
+                        continue;
+                    }
+                    let change = match (*from == to, file.copied) {
+                        (true, _) => ChangeKind::new(
+                            to.clone(),
+                            Some(file_type(original_mode)),
+                            Some(file_type(mode)),
+                        ),
+                        (false, false) => ChangeKind::Renamed(from.clone(), to.clone()),
+                        (false, true) => ChangeKind::Copied(from.clone(), to.clone()),
+                    };
+                    changes.patched.insert(to, (Content::Text(modified), mode));
+                    change
+                }
+            };
+            changes.changes.push(change);
+        }
+
+        Ok(changes)
+    }
+
+    /// Keeps only the changes with a side below one of `filters`, for changes that weren't
+    /// detected with them.
+    pub fn retain_paths(&mut self, filters: &[PathBuf]) {
+        self.changes.retain(|change| {
+            let (original, modified) = (change.original(), change.modified());
+            (filters.iter())
+                .any(|filter| original.starts_with(filter) || modified.starts_with(filter))
+        });
     }
 
     pub fn iter(&self) -> std::slice::Iter<'_, ChangeKind> {
@@ -27,10 +188,93 @@ impl Changes {
     }
 
     pub fn original_path(&self, path: &Path) -> PathBuf {
-        self.base_dir_original.join(path)
+        join_relative(&self.base_dir_original, path)
     }
     pub fn modified_path(&self, path: &Path) -> PathBuf {
-        self.base_dir_modified.join(path)
+        join_relative(&self.base_dir_modified, path)
+    }
+    pub fn output_path(&self, path: &Path) -> PathBuf {
+        join_relative(&self.base_dir_output, path)
+    }
+
+    /// Reads the contents of `path` on the modified side.
+    pub fn read_modified(&self, path: &Path) -> std::io::Result<Content> {
+        match self.patched.get(path) {
+            Some((content, _)) => Ok(content.clone()),
+            None => read_content(self.modified_path(path)),
+        }
+    }
+    pub fn modified_mode(&self, path: &Path) -> std::io::Result<u32> {
+        match self.patched.get(path) {
+            Some(&(_, mode)) => Ok(mode),
+            None => read_mode(&self.modified_path(path)),
+        }
+    }
+
+    /// The name `path` is displayed as on the original side.
+    /// When diffing two files, `path` is empty and the file itself is shown instead.
+    pub fn original_name<'a>(&'a self, path: &'a Path) -> &'a Path {
+        match path.as_os_str().is_empty() {
+            true => &self.base_dir_original,
+            false => path,
+        }
+    }
+    pub fn modified_name<'a>(&'a self, path: &'a Path) -> &'a Path {
+        match path.as_os_str().is_empty() {
+            true => &self.base_dir_modified,
+            false => path,
+        }
+    }
+
+    /// Drops all changes whose sides are identical. The comparisons are spread across threads.
+    fn skip_unchanged(&mut self) -> Result<()> {
+        let n_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = self.changes.len().div_ceil(n_threads).max(1);
+
+        let this = &*self;
+        let unchanged = std::thread::scope(|scope| {
+            let handles: Vec<_> = this
+                .changes
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|change| this.is_unchanged(change))
+                            .collect::<Result<Vec<_>>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let mut unchanged = unchanged.into_iter().flatten();
+        self.changes.retain(|_| unchanged.next() == Some(false));
+
+        Ok(())
+    }
+
+    fn is_unchanged(&self, change: &ChangeKind) -> Result<bool> {
+        let (Some(original), Some(modified)) = change.actual(self) else {
+            return Ok(false);
+        };
+
+        let unchanged = match change {
+            ChangeKind::Symlink(_, original_type, modified_type) => {
+                original_type == modified_type
+                    && std::fs::read_link(&original)? == std::fs::read_link(&modified)?
+            }
+            _ => files_equal(&original, &modified)
+                .with_context(|| format!("failed to compare '{}'", change.inner().display()))?,
+        };
+        Ok(unchanged)
     }
 }
 
@@ -43,38 +287,181 @@ impl ChangeKind {
             ),
             ChangeKind::Added(path) => (None, Some(changes.modified_path(path))),
             ChangeKind::Removed(path) => (Some(changes.original_path(path)), None),
+            ChangeKind::Symlink(path, original, modified) => (
+                original.map(|_| changes.original_path(path)),
+                modified.map(|_| changes.modified_path(path)),
+            ),
+            ChangeKind::Renamed(from, to) | ChangeKind::Copied(from, to) => (
+                Some(changes.original_path(from)),
+                Some(changes.modified_path(to)),
+            ),
+        }
+    }
+
+    /// The path on the original side, which differs from the modified one for renames and copies.
+    pub fn original(&self) -> &Path {
+        match self {
+            ChangeKind::Renamed(from, _) | ChangeKind::Copied(from, _) => from,
+            _ => self.inner(),
         }
     }
+    pub fn modified(&self) -> &Path {
+        self.inner()
+    }
 
     pub fn inner(&self) -> &Path {
         match self {
             ChangeKind::Modified(val) => val,
             ChangeKind::Removed(val) => val,
             ChangeKind::Added(val) => val,
+            ChangeKind::Symlink(val, ..) => val,
+            ChangeKind::Renamed(_, val) => val,
+            ChangeKind::Copied(_, val) => val,
         }
     }
+
+    fn new(path: PathBuf, original: Option<FileType>, modified: Option<FileType>) -> Self {
+        match (original, modified) {
+            (Some(FileType::File), Some(FileType::File)) => ChangeKind::Modified(path),
+            (Some(FileType::File), None) => ChangeKind::Removed(path),
+            (None, Some(FileType::File)) => ChangeKind::Added(path),
+            _ => ChangeKind::Symlink(path, original, modified),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Content {
+    Text(String),
+    Binary(Vec<u8>),
 }
 
-fn read_diff_paths(dir: &Path) -> Result<BTreeSet<PathBuf>> {
-    let mut paths = BTreeSet::new();
-    for entry in WalkDir::new(dir) {
+impl Default for Content {
+    fn default() -> Self {
+        Content::Text(String::new())
+    }
+}
+
+impl Content {
+    /// Files containing a NUL byte early on or invalid UTF-8 are binary, like in git.
+    pub fn from_bytes(bytes: Vec<u8>) -> Content {
+        if bytes[..bytes.len().min(8000)].contains(&0) {
+            return Content::Binary(bytes);
+        }
+        match String::from_utf8(bytes) {
+            Ok(text) => Content::Text(text),
+            Err(e) => Content::Binary(e.into_bytes()),
+        }
+    }
+
+    /// The text to diff, binary contents are diffed as empty.
+    pub fn text(&self) -> &str {
+        match self {
+            Content::Text(text) => text,
+            Content::Binary(_) => "",
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Content::Text(text) => text.as_bytes(),
+            Content::Binary(bytes) => bytes,
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Content::Binary(_))
+    }
+}
+
+/// Reads the permission bits of a file, including its file type like git's `100644`.
+pub fn read_mode(path: &Path) -> std::io::Result<u32> {
+    Ok(path.symlink_metadata()?.permissions().mode())
+}
+
+/// Reads the contents of a file, or the target of a symlink.
+pub fn read_content(path: PathBuf) -> std::io::Result<Content> {
+    if path.symlink_metadata()?.is_symlink() {
+        let target = std::fs::read_link(&path)?;
+        let target = target.into_os_string().into_string().map_err(|target| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("symlink target {} is not valid UTF-8", target.display()),
+            )
+        })?;
+        return Ok(Content::Text(target));
+    }
+
+    Ok(Content::from_bytes(std::fs::read(path)?))
+}
+
+const INSTRUCTIONS_FILE: &str = "JJ-INSTRUCTIONS";
+
+/// The instructions are only a hint, so a missing or unreadable file is ignored.
+fn read_instructions(dir: &Path) -> Option<String> {
+    match dir.is_dir() {
+        true => std::fs::read_to_string(dir.join(INSTRUCTIONS_FILE)).ok(),
+        false => None,
+    }
+}
+
+fn read_diff_paths(dir: &Path, filters: &[PathBuf]) -> Result<BTreeMap<PathBuf, FileType>> {
+    let roots: Vec<_> = match filters.is_empty() {
+        true => vec![dir.to_owned()],
+        // a filter may only exist on one side
+        false => (filters.iter().map(|filter| dir.join(filter)))
+            .filter(|root| root.symlink_metadata().is_ok())
+            .collect(),
+    };
+
+    let mut paths = BTreeMap::new();
+    for entry in roots.iter().flat_map(WalkDir::new) {
         let entry = entry?;
 
         let file_type = entry.file_type();
-        ensure!(!file_type.is_symlink(), "symlinks are not supported yet");
-
-        if file_type.is_dir() || entry.file_name() == "JJ-INSTRUCTIONS" {
+        if file_type.is_dir() || entry.path() == dir.join(INSTRUCTIONS_FILE) {
             continue;
         }
+        let file_type = match file_type.is_symlink() {
+            true => FileType::Symlink,
+            false => FileType::File,
+        };
         let relative = entry.path().strip_prefix(dir)?.to_owned();
 
-        paths.insert(relative);
+        paths.insert(relative, file_type);
     }
 
     Ok(paths)
 }
 
-fn read_changes(original: &Path, modified: &Path) -> Result<Changes> {
+/// A symlinked file is read and updated where it points to, instead of diffing the link itself.
+fn resolve_file(path: &Path) -> Result<PathBuf> {
+    match !path.is_dir() && path.is_symlink() {
+        true => std::fs::canonicalize(path)
+            .with_context(|| format!("failed to resolve '{}'", path.display())),
+        false => Ok(path.to_owned()),
+    }
+}
+
+#[test]
+fn check_symlinked_file() {
+    let dir = std::env::temp_dir().join(format!("diffpatch-symlink-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("old.txt"), "a\nb\n").unwrap();
+    std::fs::write(dir.join("real.txt"), "a\nc\n").unwrap();
+    std::os::unix::fs::symlink("real.txt", dir.join("new.txt")).unwrap();
+
+    let (old, new) = (dir.join("old.txt"), dir.join("new.txt"));
+    let changes = Changes::detect(&old, &new, &new, &[]).unwrap();
+    let modified = changes.read_modified(Path::new("")).unwrap();
+    assert_eq!(modified.text(), "a\nc\n");
+    let mode = changes.modified_mode(Path::new("")).unwrap();
+    assert_eq!(mode & 0o170000, 0o100000);
+    let output = changes.output_path(Path::new(""));
+    assert_eq!(output, std::fs::canonicalize(dir.join("real.txt")).unwrap());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+fn read_changes(original: &Path, modified: &Path, filters: &[PathBuf]) -> Result<Changes> {
     ensure!(
         original.exists(),
         "{}: no such file or directory",
@@ -87,8 +474,19 @@ fn read_changes(original: &Path, modified: &Path) -> Result<Changes> {
     );
 
     match (original.is_dir(), modified.is_dir()) {
-        (true, true) => read_changes_dir(original, modified),
-        (false, false) => Err(eyre!("Diffing files is not implemented yet")),
+        (true, true) => read_changes_dir(original, modified, filters),
+        (false, false) if !filters.is_empty() => Err(eyre!(
+            "paths after -- can only be given when diffing folders"
+        )),
+        (false, false) => Ok(Changes {
+            base_dir_original: resolve_file(original)?,
+            base_dir_modified: resolve_file(modified)?,
+            base_dir_output: resolve_file(modified)?,
+            patched: HashMap::new(),
+            changes: vec![ChangeKind::Modified(PathBuf::new())],
+            instructions: None,
+            output_is_original: false,
+        }),
         _ => Err(eyre!(
             "Cannot diffpatch mix of path and directory {} and {}",
             original.display(),
@@ -97,23 +495,75 @@ fn read_changes(original: &Path, modified: &Path) -> Result<Changes> {
     }
 }
 
-fn read_changes_dir(original_dir: &Path, modified_dir: &Path) -> Result<Changes> {
-    let original_paths = read_diff_paths(original_dir)?;
-    let modified_paths = read_diff_paths(modified_dir)?;
+/// Whether two files have the same contents and permissions.
+fn files_equal(a: &Path, b: &Path) -> std::io::Result<bool> {
+    let (a_metadata, b_metadata) = (a.metadata()?, b.metadata()?);
+    if a_metadata.len() != b_metadata.len()
+        || a_metadata.permissions().mode() != b_metadata.permissions().mode()
+    {
+        return Ok(false);
+    }
+
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    loop {
+        let (len, equal) = {
+            let (buf_a, buf_b) = (a.fill_buf()?, b.fill_buf()?);
+            let len = buf_a.len().min(buf_b.len());
+            (len, buf_a[..len] == buf_b[..len])
+        };
+        if !equal {
+            return Ok(false);
+        }
+        if len == 0 {
+            return Ok(true);
+        }
+        a.consume(len);
+        b.consume(len);
+    }
+}
+
+fn join_relative(base: &Path, path: &Path) -> PathBuf {
+    match path.as_os_str().is_empty() {
+        true => base.to_owned(),
+        false => base.join(path),
+    }
+}
+
+fn read_changes_dir(
+    original_dir: &Path,
+    modified_dir: &Path,
+    filters: &[PathBuf],
+) -> Result<Changes> {
+    let original_paths = read_diff_paths(original_dir, filters)?;
+    let modified_paths = read_diff_paths(modified_dir, filters)?;
 
-    let modified = original_paths.intersection(&modified_paths);
-    let removed = original_paths.difference(&modified_paths);
-    let added = modified_paths.difference(&original_paths);
+    let modified = original_paths.iter().filter_map(|(path, &original)| {
+        let modified = *modified_paths.get(path)?;
+        Some((path, Some(original), Some(modified)))
+    });
+    let removed = original_paths
+        .iter()
+        .filter(|(path, _)| !modified_paths.contains_key(*path))
+        .map(|(path, &original)| (path, Some(original), None));
+    let added = modified_paths
+        .iter()
+        .filter(|(path, _)| !original_paths.contains_key(*path))
+        .map(|(path, &modified)| (path, None, Some(modified)));
 
     let changes = modified
-        .map(|p| ChangeKind::Modified(p.to_owned()))
-        .chain(removed.map(|p| ChangeKind::Removed(p.to_owned())))
-        .chain(added.map(|p| ChangeKind::Added(p.to_owned())))
+        .chain(removed)
+        .chain(added)
+        .map(|(path, original, modified)| ChangeKind::new(path.to_owned(), original, modified))
         .collect();
 
     Ok(Changes {
         base_dir_original: original_dir.to_owned(),
         base_dir_modified: modified_dir.to_owned(),
+        base_dir_output: modified_dir.to_owned(),
+        patched: HashMap::new(),
         changes,
+        instructions: None,
+        output_is_original: false,
     })
 }
@@ -1,7 +1,13 @@
 use color_eyre::Result;
-use color_eyre::eyre::{Context, bail, eyre};
+use color_eyre::eyre::{Context, OptionExt, bail, ensure, eyre};
+use nu_ansi_term::{Color, Style};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use crate::diff_patch::Action;
+use crate::editor;
+use crate::git::GitCommand;
+
 pub enum Interface {
     Direct,
     Fullscreen,
@@ -16,7 +22,46 @@ impl FromStr for Interface {
             "fullscreen" => Ok(Interface::Fullscreen),
             "inline-clear" => Ok(Interface::InlineClear),
             other => Err(ParseEnumError(
-                &["direct", "inline-clear"],
+                &["direct", "fullscreen", "inline-clear"],
+                other.to_owned(),
+            )),
+        }
+    }
+}
+
+/// The jj or git command diffpatch is used by, which decides the wording and direction of the
+/// diff. Only the jj commands can be given with `--mode`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    Commit,
+    Split,
+    Squash,
+    Restore,
+    Diffedit,
+    Git(GitCommand),
+}
+impl Mode {
+    /// Whether the changes are shown from the modified to the original side, because accepting
+    /// them takes them away.
+    pub fn is_reversed(self) -> bool {
+        matches!(
+            self,
+            Mode::Restore | Mode::Git(GitCommand::Reset | GitCommand::Checkout)
+        )
+    }
+}
+impl FromStr for Mode {
+    type Err = ParseEnumError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "commit" => Ok(Mode::Commit),
+            "split" => Ok(Mode::Split),
+            "squash" => Ok(Mode::Squash),
+            "restore" => Ok(Mode::Restore),
+            "diffedit" => Ok(Mode::Diffedit),
+            other => Err(ParseEnumError(
+                &["commit", "split", "squash", "restore", "diffedit"],
                 other.to_owned(),
             )),
         }
@@ -31,9 +76,20 @@ pub struct Options {
     // interface options
     pub interface: Interface,
     pub immediate_command: bool,
+    pub confirm: bool,
+    pub color: bool,
+    pub styles: Styles,
+    /// Keys bound to actions instead of their defaults, with where they were configured like
+    /// `config.toml: keys.split`.
+    pub keys: Vec<(Action, char, String)>,
+
+    // output options
+    /// Write the selected changes as a patch instead of applying them, `-` for stdout.
+    pub output_patch: Option<PathBuf>,
 
     // misc
-    pub jj_subcommand: Option<String>,
+    pub editor: Option<String>,
+    pub mode: Option<Mode>,
 }
 
 impl Default for Options {
@@ -44,18 +100,156 @@ impl Default for Options {
 
             interface: Interface::Direct,
             immediate_command: true,
+            confirm: true,
+            color: true,
+            styles: Styles::default(),
+            keys: Vec::new(),
+
+            output_patch: None,
 
-            jj_subcommand: None,
+            editor: None,
+            mode: None,
+        }
+    }
+}
+
+pub struct Styles {
+    pub prompt: Style,
+    pub error: Style,
+    pub header: Style,
+}
+
+impl Default for Styles {
+    fn default() -> Self {
+        Self {
+            prompt: Color::Blue.bold(),
+            error: Color::Red.bold(),
+            header: Color::White.bold(),
+        }
+    }
+}
+
+impl Styles {
+    pub fn plain() -> Self {
+        Self {
+            prompt: Style::new(),
+            error: Style::new(),
+            header: Style::new(),
         }
     }
 }
 
 impl Options {
+    /// Loads `~/.config/diffpatch/config.toml` and then `.diffpatch.toml` in the root of the
+    /// surrounding repository, each overriding the options set before.
+    pub fn load_config_files(&mut self, cwd: &Path) -> Result<&mut Options> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")));
+        let user_config = config_dir.map(|dir| dir.join("diffpatch").join("config.toml"));
+        let repo_config = editor::find_vcs_dir(cwd)
+            .and_then(|vcs_dir| Some(vcs_dir.parent()?.join(".diffpatch.toml")));
+
+        let configs = (user_config.into_iter().map(|path| (path, true)))
+            .chain(repo_config.map(|path| (path, false)));
+        for (path, trusted) in configs {
+            let content = match std::fs::read_to_string(&path) {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("could not read {}", path.display()));
+                }
+            };
+            let table: toml::Table = content
+                .parse()
+                .with_context(|| format!("{} could not be parsed", path.display()))?;
+            // the editor is run as a command, which a cloned repository must not be able to set
+            ensure!(
+                trusted || !table.contains_key("editor"),
+                "{}: editor can only be set in the user config",
+                path.display()
+            );
+            self.load_config(&ConfigTable {
+                path: &path,
+                prefix: String::new(),
+                table: &table,
+            })?;
+        }
+
+        Ok(self)
+    }
+
+    fn load_config(&mut self, config: &ConfigTable) -> Result<()> {
+        config.check_keys(&[
+            "context-len",
+            "interface",
+            "immediate-command",
+            "confirm",
+            "editor",
+            "color",
+            "colors",
+            "keys",
+        ])?;
+        config.get(&mut self.context_len, "context-len", |value| {
+            Ok(usize::try_from(as_integer(value)?)?)
+        })?;
+
+        config.get(&mut self.interface, "interface", |value| {
+            Ok(as_str(value)?.parse()?)
+        })?;
+        config.get(&mut self.immediate_command, "immediate-command", as_bool)?;
+        config.get(&mut self.confirm, "confirm", as_bool)?;
+        config.get(&mut self.color, "color", as_bool)?;
+
+        config.get(&mut self.editor, "editor", |value| {
+            Ok(Some(as_str(value)?.to_owned()))
+        })?;
+
+        if let Some(colors) = config.table("colors")? {
+            colors.check_keys(&["prompt", "error", "header"])?;
+            let style = |value: &toml::Value| parse_style(as_str(value)?);
+            colors.get(&mut self.styles.prompt, "prompt", style)?;
+            colors.get(&mut self.styles.error, "error", style)?;
+            colors.get(&mut self.styles.header, "header", style)?;
+        }
+        if let Some(keys) = config.table("keys")? {
+            for name in keys.table.keys() {
+                let action = Action::from_name(name)
+                    .ok_or_else(|| eyre!("{}: unknown action keys.{name}", keys.path.display()))?;
+                let mut key = ' ';
+                keys.get(&mut key, name, |value| {
+                    let mut chars = as_str(value)?.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => Ok(c),
+                        _ => bail!("expected a single character"),
+                    }
+                })?;
+                let origin = format!("{}: keys.{name}", keys.path.display());
+                self.keys.push((action, key, origin));
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn load_env(&mut self) -> Result<&mut Options> {
         get_env(&mut self.context_len, "DIFFPATCH_CONTEXT_LEN")?;
 
         get_env(&mut self.interface, "DIFFPATCH_INTERFACE")?;
         get_env_bool(&mut self.immediate_command, "DIFFPATCH_IMMEDIATE_COMMAND")?;
+        get_env_bool(&mut self.confirm, "DIFFPATCH_CONFIRM")?;
+        get_env_bool(&mut self.color, "DIFFPATCH_COLOR")?;
+
+        if let Ok(editor) = std::env::var("DIFFPATCH_EDITOR") {
+            self.editor = Some(editor);
+        }
+        if let Ok(mode) = std::env::var("DIFFPATCH_MODE") {
+            let parsed = mode
+                .parse()
+                .with_context(|| eyre!("DIFFPATCH_MODE={} could not be parsed", mode))?;
+            self.mode = Some(parsed);
+        }
 
         Ok(self)
     }
@@ -83,8 +277,129 @@ fn get_env_bool(out: &mut bool, env_name: &str) -> Result<()> {
     Ok(())
 }
 
+/// A table of a config file, which knows where it comes from to report errors.
+struct ConfigTable<'a> {
+    path: &'a Path,
+    /// The keys of the parent tables, like `colors.`
+    prefix: String,
+    table: &'a toml::Table,
+}
+
+impl ConfigTable<'_> {
+    fn get<T>(
+        &self,
+        out: &mut T,
+        key: &str,
+        parse: impl FnOnce(&toml::Value) -> Result<T>,
+    ) -> Result<()> {
+        if let Some(value) = self.table.get(key) {
+            *out = parse(value).with_context(|| {
+                let path = self.path.display();
+                eyre!("{path}: {}{key} = {value} could not be parsed", self.prefix)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn table(&self, key: &str) -> Result<Option<ConfigTable<'_>>> {
+        let Some(value) = self.table.get(key) else {
+            return Ok(None);
+        };
+        let table = value.as_table().ok_or_else(|| {
+            eyre!(
+                "{}: {}{key} must be a table",
+                self.path.display(),
+                self.prefix
+            )
+        })?;
+        Ok(Some(ConfigTable {
+            path: self.path,
+            prefix: format!("{}{key}.", self.prefix),
+            table,
+        }))
+    }
+
+    /// Rejects unknown keys, which are most likely typos.
+    fn check_keys(&self, known: &[&str]) -> Result<()> {
+        match self.table.keys().find(|key| !known.contains(&key.as_str())) {
+            Some(key) => bail!("{}: unknown key {}{key}", self.path.display(), self.prefix),
+            None => Ok(()),
+        }
+    }
+}
+
+fn as_str(value: &toml::Value) -> Result<&str> {
+    value.as_str().ok_or_eyre("expected a string")
+}
+fn as_bool(value: &toml::Value) -> Result<bool> {
+    value.as_bool().ok_or_eyre("expected true or false")
+}
+fn as_integer(value: &toml::Value) -> Result<i64> {
+    value.as_integer().ok_or_eyre("expected a number")
+}
+
+#[test]
+fn check_parse_style() {
+    assert_eq!(parse_style("blue bold").unwrap(), Color::Blue.bold());
+    assert_eq!(parse_style("bright-red").unwrap(), Color::LightRed.normal());
+    assert_eq!(
+        parse_style("underline 208").unwrap(),
+        Color::Fixed(208).underline()
+    );
+    assert_eq!(parse_style("").unwrap(), Style::new());
+    assert!(parse_style("blue bolt").is_err());
+}
+/// Parses styles like git's color config, e.g. `red bold` or `bright-blue underline`.
+fn parse_style(s: &str) -> Result<Style> {
+    let mut style = Style::new();
+    for word in s.split_whitespace() {
+        let color = match word.strip_prefix("bright-").unwrap_or(word) {
+            "black" => Color::Black,
+            "red" => Color::Red,
+            "green" => Color::Green,
+            "yellow" => Color::Yellow,
+            "blue" => Color::Blue,
+            "magenta" => Color::Magenta,
+            "cyan" => Color::Cyan,
+            "white" => Color::White,
+            "bold" => {
+                style = style.bold();
+                continue;
+            }
+            "dim" => {
+                style = style.dimmed();
+                continue;
+            }
+            "italic" => {
+                style = style.italic();
+                continue;
+            }
+            "underline" => {
+                style = style.underline();
+                continue;
+            }
+            other => match other.parse() {
+                Ok(n) => Color::Fixed(n),
+                Err(_) => bail!("unknown color or attribute '{word}'"),
+            },
+        };
+        style.foreground = Some(match (word.starts_with("bright-"), color) {
+            (true, Color::Black) => Color::DarkGray,
+            (true, Color::Red) => Color::LightRed,
+            (true, Color::Green) => Color::LightGreen,
+            (true, Color::Yellow) => Color::LightYellow,
+            (true, Color::Blue) => Color::LightBlue,
+            (true, Color::Magenta) => Color::LightMagenta,
+            (true, Color::Cyan) => Color::LightCyan,
+            (true, Color::White) => Color::LightGray,
+            (_, color) => color,
+        });
+    }
+    Ok(style)
+}
+
 #[derive(Debug)]
-pub struct ParseEnumError(&'static [&'static str], String);
+pub struct ParseEnumError(pub &'static [&'static str], pub String);
 impl std::fmt::Display for ParseEnumError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Expected one of ")?;
@@ -4,33 +4,41 @@ use std::iter;
 use std::ops::ControlFlow;
 use std::os::fd::AsFd;
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitCode};
+use std::process::ExitCode;
 
 use color_eyre::Result;
-use color_eyre::eyre::{Context, ensure, eyre};
-use diffy::{Hunk, Patch, PatchFormatter};
+use color_eyre::eyre::{Context, bail, ensure, eyre};
+use diffy::{Hunk, Line, Patch, PatchFormatter};
 use nu_ansi_term::{Color, Style};
+use regex::Regex;
 use termion::cursor::DetectCursorPos;
 use termion::event::Key;
 use termion::input::TermRead;
 use termion::raw::{IntoRawMode, RawTerminal};
 use termion::screen::{AlternateScreen, IntoAlternateScreen};
 
-use crate::changes::{ChangeKind, Changes};
-use crate::config::{Interface, Options};
+use crate::changes::{ChangeKind, Changes, Content, read_content, read_mode};
+use crate::config::{Interface, Mode, Options, Styles};
 use crate::count_lines::CountLines;
+use crate::git::GitCommand;
+use crate::{apply, output_patch};
+use crate::{editor, terminal};
 
 pub struct DiffPatch {
     options: Options,
     formatter: PatchFormatter,
     plain_formatter: PatchFormatter,
+    /// [`Action::KEYS`] with the keys from the config.
+    bindings: Vec<Binding>,
 
     stdin: std::io::Stdin,
     stdout: MaybeRawTerminal<std::io::Stdout>,
 
-    _alternate: Option<AlternateScreen<std::io::Stdout>>,
+    alternate: Option<AlternateScreen<std::io::Stdout>>,
 
     inline_uncleared_lines: (u16, u16),
+    /// Printed above the next hunk, so that it is cleared along with it.
+    help: Option<String>,
 }
 
 const STEP_HUNK_LAST: usize = usize::MAX;
@@ -49,11 +57,23 @@ impl Step {
     }
 }
 
+/// The state of a file before a decision was made, restored by [`Action::Undo`].
+struct Snapshot<'a> {
+    step: Step,
+    resolutions: Vec<Option<bool>>,
+    patch: Patch<'a, str>,
+}
+
 impl DiffPatch {
     pub fn new(mut options: Options) -> Result<Self> {
         let stdin = std::io::stdin();
         let stdout = std::io::stdout();
         let is_tty = termion::is_tty(&stdout);
+        // the hunks and prompts are written to stdout as well, which would end up in the patch
+        ensure!(
+            is_tty || options.output_patch.as_deref() != Some(Path::new("-")),
+            "The patch can only be printed to a terminal, use --output-patch <FILE> instead"
+        );
 
         if !is_tty {
             options.immediate_command = false;
@@ -65,7 +85,14 @@ impl DiffPatch {
                 options.interface,
                 Interface::InlineClear | Interface::Fullscreen
             );
+        let fullscreen = matches!(options.interface, Interface::Fullscreen);
         let stdout = if wants_raw_terminal {
+            terminal::install_restore_handlers(fullscreen)
+                .context("Could not prepare restoring the terminal")?;
+            // the direct interface never redraws, so resizing must not clear the terminal
+            if !matches!(options.interface, Interface::Direct) {
+                terminal::install_resize_handler().context("Could not watch the terminal size")?;
+            }
             let term = stdout
                 .into_raw_mode()
                 .context("Could not set terminal into raw mode")?;
@@ -75,7 +102,7 @@ impl DiffPatch {
             MaybeRawTerminal::Normal(stdout)
         };
 
-        let alternate = matches!(options.interface, Interface::Fullscreen)
+        let alternate = fullscreen
             .then(|| -> std::io::Result<_> {
                 let mut alt = std::io::stdout().into_alternate_screen()?;
                 write!(alt, "{}", termion::cursor::Goto(1, 1))?;
@@ -84,14 +111,40 @@ impl DiffPatch {
             .transpose()
             .context("Could not open fullscreen terminal")?;
 
+        let mut bindings = Action::KEYS.to_vec();
+        let mut origins = vec![None; bindings.len()];
+        for (action, key, origin) in &options.keys {
+            if let Some(i) = bindings.iter().position(|binding| binding.1 == *action) {
+                bindings[i].0 = *key;
+                origins[i] = Some(origin.as_str());
+            }
+        }
+        for (i, (key, ..)) in bindings.iter().enumerate() {
+            if let Some(j) = bindings[..i].iter().position(|binding| binding.0 == *key) {
+                // the defaults don't overlap, so at least one of them was configured
+                let origin = origins[i].or(origins[j]).unwrap_or_default();
+                bail!("{origin} = '{key}' is bound to more than one action");
+            }
+        }
+
+        let formatter = match options.color {
+            true => PatchFormatter::new().with_color(),
+            false => {
+                options.styles = Styles::plain();
+                PatchFormatter::new()
+            }
+        };
+
         Ok(DiffPatch {
             options,
-            formatter: PatchFormatter::new().with_color(),
+            formatter,
             plain_formatter: PatchFormatter::new(),
+            bindings,
             stdin,
             stdout,
-            _alternate: alternate,
+            alternate,
             inline_uncleared_lines: (0, 0),
+            help: None,
         })
     }
 
@@ -100,25 +153,25 @@ impl DiffPatch {
             return Ok(ExitCode::SUCCESS);
         }
 
-        let mut resolutions = vec![Vec::<bool>::new(); changes.changes.len()];
-
-        let contents: Vec<(String, String)> = changes
+        let contents: Vec<(Content, Content)> = changes
             .iter()
             .map(|change| {
                 let (original, modified) = change.actual(changes);
 
                 let original_content = original
-                    .map(std::fs::read_to_string)
+                    .map(read_content)
                     .transpose()
                     .with_context(|| {
-                        format!("failed to read original '{}'", change.inner().display())
+                        let name = changes.original_name(change.original());
+                        format!("failed to read original '{}'", name.display())
                     })?
                     .unwrap_or_default();
                 let modified_content = modified
-                    .map(std::fs::read_to_string)
+                    .map(|_| changes.read_modified(change.modified()))
                     .transpose()
                     .with_context(|| {
-                        format!("failed to read modified '{}'", change.inner().display())
+                        let name = changes.modified_name(change.modified());
+                        format!("failed to read modified '{}'", name.display())
                     })?
                     .unwrap_or_default();
 
@@ -126,96 +179,210 @@ impl DiffPatch {
             })
             .collect::<Result<_>>()?;
 
+        let all_metas: Vec<Vec<MetaHunk>> = changes
+            .iter()
+            .map(|change| {
+                let mut metas = Vec::new();
+                match change {
+                    ChangeKind::Renamed(from, to) => {
+                        metas.push(MetaHunk::Rename(from.clone(), to.clone()))
+                    }
+                    ChangeKind::Copied(from, to) => {
+                        metas.push(MetaHunk::Copy(from.clone(), to.clone()))
+                    }
+                    _ => {}
+                }
+                if let (
+                    ChangeKind::Modified(_) | ChangeKind::Renamed(..) | ChangeKind::Copied(..),
+                    (Some(original), Some(_)),
+                ) = (change, change.actual(changes))
+                {
+                    let original_mode = read_mode(&original)?;
+                    let modified_mode = changes.modified_mode(change.modified())?;
+                    if original_mode != modified_mode {
+                        metas.push(MetaHunk::Mode(original_mode, modified_mode));
+                    }
+                }
+                Ok(metas)
+            })
+            .collect::<Result<_>>()?;
+
         let mut patches: Vec<Patch<str>> = changes
             .iter()
             .zip(&contents)
             .map(|(change, (original, modified))| {
                 let mut diff_options = diffy::DiffOptions::new();
-                let path = change.inner();
                 diff_options.set_context_len(self.options.context_len);
-                diff_options.set_original_filename(path.display().to_string());
-                diff_options.set_modified_filename(path.display().to_string());
-                diff_options.create_patch(original, modified)
+                let original_name = changes.original_name(change.original());
+                let modified_name = changes.modified_name(change.modified());
+                let original_name = original_name.display().to_string();
+                let modified_name = modified_name.display().to_string();
+                diff_options.set_original_filename(original_name);
+                diff_options.set_modified_filename(modified_name);
+                match original.is_binary() || modified.is_binary() {
+                    true => diff_options.create_patch("", ""),
+                    false => diff_options.create_patch(original.text(), modified.text()),
+                }
+            })
+            .collect();
+
+        // `None` while the hunk is undecided
+        let mut resolutions: Vec<Vec<Option<bool>>> = (changes.iter().zip(&contents))
+            .zip(all_metas.iter().zip(&patches))
+            .map(|((change, content), (metas, patch))| {
+                vec![None; n_logical_hunks(change, content, metas, patch.hunks().len())]
             })
             .collect();
 
         let mut step = Step::default();
         let mut prev_step = Step::invalid();
+        let mut history: Vec<Snapshot> = Vec::new();
+        // shown above the first hunk, later only on request
+        self.help = changes.instructions.clone();
 
         loop {
             let change = &changes.changes[step.change];
+            let content = &contents[step.change];
+            let metas = &all_metas[step.change];
 
             let patch = &mut patches[step.change];
             let n_hunks = patch.hunks().len();
-            let n_hunks_logical = n_hunks.max(1);
-
-            resolutions[step.change].resize(n_hunks_logical, false);
+            let n_hunks_logical = n_logical_hunks(change, content, metas, n_hunks);
 
             if step.hunk == STEP_HUNK_LAST {
-                step.hunk = n_hunks.saturating_sub(1);
+                step.hunk = n_hunks_logical - 1;
             }
+            let hunk_index = step.hunk.checked_sub(metas.len());
 
-            self.step(change, patch, prev_step, step)?;
+            self.step(changes, content, metas, patch, prev_step, step)?;
 
             let kind = match (self.options.reversed, change) {
-                (_, ChangeKind::Modified(_)) => "this hunk",
+                _ if hunk_index.is_none() => metas[step.hunk].kind(),
+                (_, ChangeKind::Modified(_) | ChangeKind::Renamed(..) | ChangeKind::Copied(..)) => {
+                    "this hunk"
+                }
                 (false, ChangeKind::Removed(_)) | (true, ChangeKind::Added(_)) => "deletion",
-                (false, ChangeKind::Added(_)) | (true, ChangeKind::Removed(_)) => "deletion",
+                (false, ChangeKind::Added(_)) | (true, ChangeKind::Removed(_)) => "addition",
+                (_, ChangeKind::Symlink(..)) => "symlink change",
             };
-            let message = match self.options.jj_subcommand.as_deref().unwrap_or_default() {
-                "diffedit" => format!("Keep {kind}"),
-                "restore" => format!("Discard {kind}"),
-                "commit" | _ => format!("Stage {kind}"),
+            let verb = match self.options.mode {
+                Some(Mode::Diffedit) => "Keep",
+                Some(Mode::Restore | Mode::Git(GitCommand::Checkout)) => "Discard",
+                Some(Mode::Git(GitCommand::Reset)) => "Unstage",
+                _ => "Stage",
             };
 
+            let content_hunk = hunk_index.filter(|&i| i < n_hunks);
+            let can_split = content_hunk.is_some_and(|i| patch.clone().split_hunk_at(i).len() > 1);
+            let keys = (self.bindings.iter())
+                .filter(|(_, action, _)| match action {
+                    Action::Split => can_split,
+                    Action::Edit | Action::SelectLines => content_hunk.is_some(),
+                    Action::Undo => !history.is_empty(),
+                    Action::NextUndecided | Action::PrevUndecided => {
+                        find_undecided(&resolutions, step, true).is_some_and(|s| s != step)
+                    }
+                    Action::Next | Action::Prev | Action::Goto | Action::Search => {
+                        changes.changes.len() > 1 || n_hunks_logical > 1
+                    }
+                    Action::Instructions => changes.instructions.is_some(),
+                    Action::Clear => false,
+                    _ => true,
+                })
+                .map(|(key, ..)| key.to_string())
+                .collect::<Vec<_>>();
+
             let action = self.ask_action(&format!(
-                "({}/{}) {} [y,n,q,a,d,e]? ",
+                "({}/{}) {verb} {kind} [{}]? ",
                 step.hunk + 1,
                 n_hunks_logical,
-                message
+                keys.join(",")
             ))?;
 
+            let decision = matches!(
+                action,
+                Action::HunkYes
+                    | Action::HunkNo
+                    | Action::FileYes
+                    | Action::FileNo
+                    | Action::Split
+                    | Action::Edit
+                    | Action::SelectLines
+            );
+            if decision {
+                history.push(Snapshot {
+                    step,
+                    resolutions: resolutions[step.change].clone(),
+                    patch: patch.clone(),
+                });
+            }
+
             match action {
-                Action::HunkYes => resolutions[step.change][step.hunk] = true,
-                Action::HunkNo => resolutions[step.change][step.hunk] = false,
+                Action::HunkYes => resolutions[step.change][step.hunk] = Some(true),
+                Action::HunkNo => resolutions[step.change][step.hunk] = Some(false),
                 Action::FileYes => resolutions[step.change][..n_hunks_logical]
                     .iter_mut()
-                    .for_each(|x| *x = true),
+                    .for_each(|x| *x = Some(true)),
                 Action::FileNo => resolutions[step.change][..n_hunks_logical]
                     .iter_mut()
-                    .for_each(|x| *x = false),
+                    .for_each(|x| *x = Some(false)),
                 _ => {}
             }
 
             let mut finish = false;
+            let mut undo = None;
+            let mut jump = None;
+            let mut advance = false;
 
             prev_step = step;
             match action {
-                Action::HunkYes => step.hunk += 1,
-                Action::HunkNo => step.hunk += 1,
-                Action::FileYes | Action::FileNo => {
-                    step.change += 1;
-                    step.hunk = 0;
+                Action::HunkYes | Action::HunkNo | Action::FileYes | Action::FileNo => {
+                    advance = true
                 }
                 Action::Quit => {
-                    step = Step::invalid();
-                    finish = true;
+                    let undecided = resolutions.iter().flatten().filter(|r| r.is_none()).count();
+                    let msg = format!(
+                        "{undecided} undecided hunk(s) will be treated as 'n'. Quit anyway [y,n]? "
+                    );
+                    if undecided == 0 || self.ask_yes_no(&msg)? {
+                        step = Step::invalid();
+                        finish = true;
+                    }
                 }
-                Action::Edit => match patch.hunks_mut().get_mut(step.hunk) {
-                    Some(hunk) => {
-                        let display_hunk = reverse_if(hunk, self.options.reversed);
-                        let hunk_str = self.plain_formatter.fmt_hunk(&display_hunk).to_string();
-                        let new_hunk = self.edit(&hunk_str)?;
-                        let new_hunk = Hunk::from_str(new_hunk.leak(), true)?;
-                        *hunk = reverse_if(&new_hunk, self.options.reversed).into_owned();
-                        resolutions[step.change][step.hunk] = true;
-                        step.hunk += 1;
+                Action::Edit => match hunk_index.filter(|&i| i < n_hunks) {
+                    Some(i) => {
+                        if let Some(new_hunk) = self.edit_hunk(content.0.text(), patch, i)? {
+                            patch.hunks_mut()[i] = new_hunk;
+                            resolutions[step.change][step.hunk] = Some(true);
+                            advance = true;
+                        }
                     }
                     None => self.write_error("Sorry, cannot edit this hunk")?,
                 },
+                Action::SelectLines => match hunk_index.filter(|&i| i < n_hunks) {
+                    Some(i) => {
+                        let display_hunk = reverse_if(&patch.hunks()[i], self.options.reversed);
+                        if let Some(new_hunk) = self.select_lines(&display_hunk)? {
+                            let new_hunk = Hunk::from_str(new_hunk.leak(), true);
+                            let new_hunk = new_hunk
+                                .map(|hunk| reverse_if(&hunk, self.options.reversed).into_owned());
+                            match new_hunk {
+                                Ok(new_hunk)
+                                    if hunk_applies(content.0.text(), patch, i, &new_hunk) =>
+                                {
+                                    patch.hunks_mut()[i] = new_hunk;
+                                    resolutions[step.change][step.hunk] = Some(true);
+                                    advance = true;
+                                }
+                                _ => self.write_error("The selected lines do not apply")?,
+                            }
+                        }
+                    }
+                    None => self.write_error("Sorry, cannot select lines of this hunk")?,
+                },
                 Action::Next => {
                     let last = step.change == changes.changes.len() - 1
-                        && step.hunk == n_hunks.saturating_sub(1);
+                        && step.hunk == n_hunks_logical - 1;
                     if !last {
                         step.hunk += 1;
                     }
@@ -228,8 +395,21 @@ impl DiffPatch {
                         step.hunk = usize::MAX;
                     }
                 }
+                Action::NextUndecided | Action::PrevUndecided => {
+                    let forward = matches!(action, Action::NextUndecided);
+                    match find_undecided(&resolutions, step, forward) {
+                        Some(target) if target != step => jump = Some(target),
+                        _ => self.write_error("No other undecided hunk")?,
+                    }
+                }
                 Action::Split => {
-                    let split_range = patch.split_hunk_at(step.hunk);
+                    let split_range = match hunk_index.filter(|&i| i < n_hunks) {
+                        Some(i) => {
+                            let range = patch.split_hunk_at(i);
+                            range.start + metas.len()..range.end + metas.len()
+                        }
+                        None => step.hunk..step.hunk + 1,
+                    };
                     if split_range.len() == 1 {
                         self.write_error("Sorry, cannot split this hunk")?;
                     } else {
@@ -242,44 +422,144 @@ impl DiffPatch {
                         );
                     }
                 }
+                Action::Goto => {
+                    let hunks = list_hunks(
+                        changes,
+                        &contents,
+                        &all_metas,
+                        &patches,
+                        self.options.reversed,
+                    );
+                    jump = self.goto(changes, &hunks, &resolutions)?;
+                }
+                Action::Search => {
+                    let hunks = list_hunks(
+                        changes,
+                        &contents,
+                        &all_metas,
+                        &patches,
+                        self.options.reversed,
+                    );
+                    jump = self.search(&hunks, step)?;
+                }
+                Action::Help => {
+                    self.help = Some(Action::help(&self.bindings, &verb.to_lowercase()))
+                }
+                Action::Instructions => self.help = changes.instructions.clone(),
+                Action::Undo => match history.pop() {
+                    Some(snapshot) => undo = Some(snapshot),
+                    None => self.write_error("Nothing to undo")?,
+                },
                 Action::Exit => return Ok(ExitCode::FAILURE),
                 Action::Clear | Action::None => (),
             }
-            if step.hunk != STEP_HUNK_LAST
-                && (n_hunks == 0 && step.hunk > 0 || n_hunks > 0 && step.hunk >= n_hunks)
-            {
-                step.hunk = 0;
-                step.change += 1;
+
+            if advance {
+                // like git, decided hunks are skipped until all hunks are decided
+                match find_undecided(&resolutions, step, true) {
+                    Some(undecided) => jump = Some(undecided),
+                    None => finish = true,
+                }
+            }
+            if let Some(snapshot) = undo {
+                resolutions[snapshot.step.change] = snapshot.resolutions;
+                patches[snapshot.step.change] = snapshot.patch;
+                jump = Some(snapshot.step);
+            }
+            if let Some(target) = jump {
+                step = target;
+            } else {
+                // cancelled edits and impossible splits are not worth undoing
+                let unchanged = history.last().is_some_and(|snapshot| {
+                    snapshot.step == step
+                        && snapshot.resolutions == resolutions[step.change]
+                        && snapshot.patch == patches[step.change]
+                });
+                if decision && unchanged {
+                    history.pop();
+                }
+
+                if step.hunk != STEP_HUNK_LAST && step.hunk >= n_hunks_logical {
+                    step.hunk = 0;
+                    step.change += 1;
+                }
             }
             if step.change >= changes.changes.len() {
                 finish = true;
             }
 
             if let Action::Clear = action {
-                self.clear_all()?;
+                terminal::request_redraw();
             } else {
                 let clear_header = prev_step.change != step.change;
                 self.clear(clear_header)?;
             }
 
             if finish {
-                break;
+                if !self.options.confirm {
+                    break;
+                }
+                let summary = self.summary(changes, &all_metas, &patches, &resolutions)?;
+                // with --output-patch, nothing is written but the patch
+                let prompt = match self.options.output_patch {
+                    Some(_) => "Output this patch [y,n,b]? ",
+                    None => "Write these changes [y,n,b]? ",
+                };
+                match self.ask_choice(prompt, &['y', 'n', 'b'])? {
+                    Some('y') => break,
+                    Some('b') => {
+                        self.inline_uncleared_lines.1 = summary;
+                        self.clear(true)?;
+                        step = prev_step;
+                        prev_step = Step::invalid();
+                    }
+                    _ => return Ok(ExitCode::FAILURE),
+                }
             }
         }
 
-        for (((change, patch), (original, _)), file_resolution) in changes
+        let mut outcomes = Vec::new();
+        for ((((change, patch), content), metas), file_resolution) in changes
             .iter()
             .zip(&mut patches)
             .zip(&contents)
+            .zip(&all_metas)
             .zip(&mut resolutions)
         {
-            file_resolution.resize(patch.hunks().len().max(1), false);
-            for (hunk, &hunk_resolution) in patch.hunks_mut().iter_mut().zip(&*file_resolution) {
+            let n_hunks_logical = n_logical_hunks(change, content, metas, patch.hunks().len());
+            file_resolution.resize(n_hunks_logical, None);
+            let file_resolution: Vec<bool> =
+                file_resolution.iter().map(|r| r.unwrap_or(false)).collect();
+            let (meta_resolution, hunk_resolution) = file_resolution.split_at(metas.len());
+
+            for (hunk, &hunk_resolution) in patch.hunks_mut().iter_mut().zip(hunk_resolution) {
                 if hunk_resolution == false {
                     *hunk = Hunk::default();
                 }
             }
-            apply_change(changes, change, original, patch, file_resolution)?;
+            let resolution = (meta_resolution, hunk_resolution);
+            outcomes.push(apply::outcome(
+                changes, change, content, metas, patch, resolution,
+            )?);
+        }
+
+        match &self.options.output_patch {
+            Some(path) => {
+                let mut out = Vec::new();
+                output_patch::write_patch(&mut out, changes, &outcomes, self.options.context_len)?;
+                if path == Path::new("-") {
+                    // leave the fullscreen interface so that the patch stays visible
+                    self.alternate = None;
+                    std::io::stdout().write_all(&out)?;
+                } else {
+                    std::fs::write(path, out)
+                        .with_context(|| format!("could not write '{}'", path.display()))?;
+                }
+            }
+            None => {
+                let _deferred = terminal::defer_signals()?;
+                apply::write_outcomes(changes, &outcomes)?
+            }
         }
 
         Ok(ExitCode::SUCCESS)
@@ -287,24 +567,51 @@ impl DiffPatch {
 
     fn step(
         &mut self,
-        change: &ChangeKind,
+        changes: &Changes,
+        content: &(Content, Content),
+        metas: &[MetaHunk],
         patch: &Patch<'_, str>,
         prev_step: Step,
         step: Step,
     ) -> Result<()> {
-        let size = self.term_size()?;
+        // the line counts of everything drawn before are off after a resize, so start over
+        let redraw = terminal::take_redraw();
+        if redraw {
+            self.clear_all()?;
+        }
 
-        let hunk = patch.hunks().get(step.hunk);
+        let size = self.term_size()?;
+        let change = &changes.changes[step.change];
 
         let mut writer = CountLines::new(self.stdout.lock(), size.0);
 
-        if prev_step.change != step.change {
-            let path = change.inner();
-            write_header(&mut writer, Some(path), Some(path))?;
+        if redraw || prev_step.change != step.change {
+            write_header(
+                &mut writer,
+                self.options.styles.header,
+                Some(changes.original_name(change.original())),
+                Some(changes.modified_name(change.modified())),
+            )?;
             self.inline_uncleared_lines.0 = writer.take_lineno();
         }
+        if let Some(help) = self.help.take() {
+            write!(writer, "{help}")?;
+        }
 
-        if let Some(hunk) = hunk {
+        let Some(hunk_index) = step.hunk.checked_sub(metas.len()) else {
+            metas[step.hunk].write_into(
+                &mut writer,
+                self.options.styles.header,
+                self.options.reversed,
+            )?;
+            self.inline_uncleared_lines.1 = writer.take_lineno();
+            return Ok(());
+        };
+
+        if let Some(summary) = binary_summary(change, content, self.options.reversed) {
+            writeln!(writer, "{summary}")?;
+            self.inline_uncleared_lines.1 = writer.take_lineno();
+        } else if let Some(hunk) = patch.hunks().get(hunk_index) {
             self.formatter
                 .write_hunk_into(&reverse_if(hunk, self.options.reversed), &mut writer)?;
             self.inline_uncleared_lines.1 = writer.take_lineno();
@@ -313,6 +620,14 @@ impl DiffPatch {
         Ok(())
     }
 
+    /// The style of inserted or deleted lines, which are only colored along with the diff.
+    fn diff_style(&self, color: Color) -> Style {
+        match self.options.color {
+            true => color.normal(),
+            false => Style::new(),
+        }
+    }
+
     fn term_size(&self) -> Result<(u16, u16), std::io::Error> {
         self.stdout
             .is_raw()
@@ -338,9 +653,10 @@ impl DiffPatch {
                 }
             }
             Interface::InlineClear => {
-                let clear_header = clear_header
-                    .then(|| std::mem::take(&mut self.inline_uncleared_lines.0))
-                    .unwrap_or(0);
+                let clear_header = match clear_header {
+                    true => std::mem::take(&mut self.inline_uncleared_lines.0),
+                    false => 0,
+                };
                 let clear_hunk = std::mem::take(&mut self.inline_uncleared_lines.1);
                 self.erase_last_lines(clear_header + clear_hunk + 1)?;
             }
@@ -352,6 +668,7 @@ impl DiffPatch {
     fn clear_all(&mut self) -> Result<()> {
         write!(self.stdout, "{}", termion::clear::All)?;
         write!(self.stdout, "{}", termion::cursor::Goto(1, 1))?;
+        self.inline_uncleared_lines = (0, 0);
         Ok(())
     }
 
@@ -371,12 +688,11 @@ impl DiffPatch {
     }
 
     fn write_error(&mut self, msg: &str) -> Result<(), std::io::Error> {
-        let error_style = nu_ansi_term::Style::new().fg(Color::Red).bold();
-        writeln!(self.stdout, "{}", error_style.paint(msg))
+        writeln!(self.stdout, "{}", self.options.styles.error.paint(msg))
     }
 
     fn ask_action(&mut self, msg: &str) -> Result<Action> {
-        let style = nu_ansi_term::Style::new().fg(Color::Blue).bold();
+        let style = self.options.styles.prompt;
 
         let mut stdout = std::io::stdout().lock();
         let mut ask = || {
@@ -387,9 +703,13 @@ impl DiffPatch {
         let result = if self.options.immediate_command {
             ask()?;
 
-            let result = self.keys(|key| {
+            let bindings = self.bindings.clone();
+            let result = self.keys(|input| {
+                let Input::Key(key) = input else {
+                    return Ok(ControlFlow::Break(Action::Clear));
+                };
                 let action = match key {
-                    Key::Char(c) => match Action::from_char(c) {
+                    Key::Char(c) => match Action::from_char(&bindings, c) {
                         Some(action) => action,
                         None => return Ok(ControlFlow::Continue(())),
                     },
@@ -410,9 +730,11 @@ impl DiffPatch {
             loop {
                 ask()?;
                 line.clear();
-                BufRead::read_line(&mut self.stdin.lock(), &mut line)?;
+                if BufRead::read_line(&mut self.stdin.lock(), &mut line)? == 0 {
+                    break Action::Exit;
+                }
 
-                match Action::from_str(line.trim_end_matches('\n')) {
+                match Action::from_str(&self.bindings, line.trim_end_matches('\n')) {
                     Some(action) => break action,
                     None => continue,
                 }
@@ -422,6 +744,130 @@ impl DiffPatch {
         Ok(result)
     }
 
+    /// Lets the user pick the changed lines of a hunk to keep.
+    /// Returns the hunk with only those changes, or `None` if the selection was cancelled.
+    fn select_lines(&mut self, hunk: &Hunk<'_, str>) -> Result<Option<String>> {
+        let changed: Vec<usize> = (hunk.lines().iter().enumerate())
+            .filter(|(_, line)| !matches!(line, Line::Context(_)))
+            .map(|(i, _)| i)
+            .collect();
+        let mut selected = vec![true; hunk.lines().len()];
+
+        if !self.options.immediate_command {
+            self.write_line_selection(hunk, &selected, None)?;
+            let msg = "Select lines to keep, e.g. 1,3-5 (empty to cancel)? ";
+            let style = self.options.styles.prompt;
+
+            let mut line = String::new();
+            loop {
+                write!(self.stdout, "{}", style.paint(msg))?;
+                self.stdout.flush()?;
+                line.clear();
+                if BufRead::read_line(&mut self.stdin.lock(), &mut line)? == 0 {
+                    return Ok(None);
+                }
+
+                let line = line.trim();
+                if line.is_empty() {
+                    return Ok(None);
+                }
+                match parse_line_selection(line, changed.len()) {
+                    Some(numbers) => {
+                        for (n, &i) in changed.iter().enumerate() {
+                            selected[i] = numbers.contains(&n);
+                        }
+                        let reversed = self.options.reversed;
+                        return Ok(Some(partial_hunk(hunk, &selected, reversed)));
+                    }
+                    None => self.write_error(&format!("Invalid selection '{line}'"))?,
+                }
+            }
+        }
+
+        let mut cursor = 0;
+        let mut lines_drawn = 0;
+        let confirmed = loop {
+            self.erase_previous_lines(lines_drawn)?;
+            lines_drawn = self.write_line_selection(hunk, &selected, Some(changed[cursor]))?;
+
+            let Some(input) = self.keys(|input| Ok(ControlFlow::Break(input)))? else {
+                break false;
+            };
+            let Input::Key(key) = input else {
+                // the hunk is redrawn once the selection is done
+                self.clear_all()?;
+                lines_drawn = 0;
+                continue;
+            };
+            match key {
+                Key::Up | Key::Char('k') => cursor = cursor.saturating_sub(1),
+                Key::Down | Key::Char('j') => cursor = (cursor + 1).min(changed.len() - 1),
+                Key::Char(' ') => selected[changed[cursor]] ^= true,
+                Key::Char('a') => {
+                    let all_selected = changed.iter().all(|&i| selected[i]);
+                    changed.iter().for_each(|&i| selected[i] = !all_selected);
+                }
+                Key::Char('\n') | Key::Char('y') => break true,
+                Key::Esc | Key::Char('q') | Key::Ctrl('c') => break false,
+                _ => {}
+            }
+        };
+        self.erase_previous_lines(lines_drawn)?;
+
+        Ok(confirmed.then(|| partial_hunk(hunk, &selected, self.options.reversed)))
+    }
+
+    /// Writes the lines of a hunk with checkboxes, returning how many terminal lines were used.
+    fn write_line_selection(
+        &mut self,
+        hunk: &Hunk<'_, str>,
+        selected: &[bool],
+        cursor: Option<usize>,
+    ) -> Result<u16> {
+        let size = self.term_size()?;
+        let mut writer = CountLines::new(self.stdout.lock(), size.0);
+
+        let mut n_changed = 0;
+        for (i, line) in hunk.lines().iter().enumerate() {
+            let (sign, text, style) = match *line {
+                Line::Context(text) => (' ', text, Style::new()),
+                Line::Delete(text) => ('-', text, self.diff_style(Color::Red)),
+                Line::Insert(text) => ('+', text, self.diff_style(Color::Green)),
+            };
+            let text = format!("{sign}{}", text.trim_end_matches('\n'));
+
+            let marker = match (line, cursor) {
+                (Line::Context(_), _) => "    ".to_owned(),
+                (_, Some(_)) => format!("[{}] ", if selected[i] { 'x' } else { ' ' }),
+                (_, None) => {
+                    n_changed += 1;
+                    format!("{n_changed:>3} ")
+                }
+            };
+            let pointer = if cursor == Some(i) { '>' } else { ' ' };
+            writeln!(writer, "{pointer}{marker}{}", style.paint(text))?;
+        }
+        if cursor.is_some() {
+            let help = "[space] toggle line, [a] toggle all, [enter] confirm, [esc] cancel";
+            writeln!(writer, "{}", self.options.styles.prompt.paint(help))?;
+        }
+        writer.flush()?;
+
+        Ok(writer.take_lineno())
+    }
+
+    fn erase_previous_lines(&mut self, n: u16) -> Result<()> {
+        if n > 0 {
+            write!(
+                self.stdout,
+                "{}\r{}",
+                termion::cursor::Up(n),
+                termion::clear::AfterCursor
+            )?;
+        }
+        Ok(())
+    }
+
     fn cursor_pos(&mut self) -> Result<(u16, u16)> {
         let term = self.stdout.get_raw()?;
 
@@ -432,11 +878,21 @@ impl DiffPatch {
         Ok(pos)
     }
 
-    fn keys<B>(&mut self, mut f: impl FnMut(Key) -> Result<ControlFlow<B>>) -> Result<Option<B>> {
+    fn keys<B>(&mut self, mut f: impl FnMut(Input) -> Result<ControlFlow<B>>) -> Result<Option<B>> {
         self.stdout.get_raw()?.activate_raw_mode()?;
 
         for key in self.stdin.lock().keys() {
-            match f(key?)? {
+            let input = match key {
+                Ok(key) => Input::Key(key),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                    if !terminal::needs_redraw() {
+                        continue;
+                    }
+                    Input::Resize
+                }
+                Err(e) => return Err(e.into()),
+            };
+            match f(input)? {
                 ControlFlow::Continue(_) => continue,
                 ControlFlow::Break(b) => {
                     self.stdout.suspend_raw_mode()?;
@@ -449,14 +905,274 @@ impl DiffPatch {
         Ok(None)
     }
 
+    /// Lets the user edit the hunk at `index` until it applies cleanly to `original`.
+    /// Returns `None` if the edit was aborted, in which case the hunk is left unchanged.
+    fn edit_hunk(
+        &mut self,
+        original: &str,
+        patch: &Patch<'_, str>,
+        index: usize,
+    ) -> Result<Option<Hunk<'static, str>>> {
+        let display_hunk = reverse_if(&patch.hunks()[index], self.options.reversed);
+        let mut hunk_str = self.plain_formatter.fmt_hunk(&display_hunk).to_string();
+
+        loop {
+            hunk_str = self.edit(&hunk_str)?;
+            if hunk_str.trim().is_empty() {
+                return Ok(None);
+            }
+
+            let error = match Hunk::from_str(hunk_str.clone().leak(), true) {
+                Ok(new_hunk) if new_hunk.lines().is_empty() => return Ok(None),
+                Ok(new_hunk) => {
+                    let new_hunk = reverse_if(&new_hunk, self.options.reversed).into_owned();
+                    match hunk_applies(original, patch, index, &new_hunk) {
+                        true => return Ok(Some(new_hunk)),
+                        false => "Your edited hunk does not apply".to_owned(),
+                    }
+                }
+                Err(err) => format!("Your edited hunk could not be parsed: {err}"),
+            };
+            self.write_error(&error)?;
+            if !self.ask_yes_no("Edit again? Saying no discards your edit [y,n]? ")? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn ask_yes_no(&mut self, msg: &str) -> Result<bool> {
+        Ok(self.ask_choice(msg, &['y', 'n'])? == Some('y'))
+    }
+
+    /// Asks until one of `choices` is given. Returns `None` if cancelled or at the end of input.
+    fn ask_choice(&mut self, msg: &str, choices: &[char]) -> Result<Option<char>> {
+        let style = self.options.styles.prompt;
+
+        if self.options.immediate_command {
+            write!(self.stdout, "{}", style.paint(msg))?;
+            self.stdout.flush()?;
+            let answer = self.keys(|input| match input {
+                Input::Key(Key::Char(c)) if choices.contains(&c) => Ok(ControlFlow::Break(Some(c))),
+                Input::Key(Key::Esc | Key::Ctrl('c')) => Ok(ControlFlow::Break(None)),
+                _ => Ok(ControlFlow::Continue(())),
+            })?;
+            writeln!(self.stdout)?;
+            return Ok(answer.flatten());
+        }
+
+        loop {
+            let Some(line) = self.ask_line(msg)? else {
+                return Ok(None);
+            };
+            let mut chars = line.trim().chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if choices.contains(&c) => return Ok(Some(c)),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Lists the accepted and rejected hunks of every file. Returns the number of lines written.
+    fn summary(
+        &mut self,
+        changes: &Changes,
+        all_metas: &[Vec<MetaHunk>],
+        patches: &[Patch<'_, str>],
+        resolutions: &[Vec<Option<bool>>],
+    ) -> Result<u16> {
+        let names: Vec<_> = (changes.iter())
+            .map(|change| {
+                changes
+                    .modified_name(change.modified())
+                    .display()
+                    .to_string()
+            })
+            .collect();
+        let width = names
+            .iter()
+            .map(|name| name.chars().count())
+            .max()
+            .unwrap_or(0);
+
+        let size = self.term_size()?;
+        let mut writer = CountLines::new(self.stdout.lock(), size.0);
+        let header_style = self.options.styles.header;
+        writeln!(writer, "{}", header_style.paint("Summary"))?;
+
+        for (((name, metas), patch), file_resolutions) in
+            names.iter().zip(all_metas).zip(patches).zip(resolutions)
+        {
+            let accepted = file_resolutions
+                .iter()
+                .filter(|&&r| r == Some(true))
+                .count();
+            let rejected = file_resolutions.len() - accepted;
+
+            let hunk_resolutions = file_resolutions.get(metas.len()..).unwrap_or_default();
+            let (mut insertions, mut deletions) = (0, 0);
+            for (hunk, _) in (patch.hunks().iter().zip(hunk_resolutions))
+                .filter(|(_, resolution)| **resolution == Some(true))
+            {
+                for line in reverse_if(hunk, self.options.reversed).lines() {
+                    match line {
+                        Line::Insert(_) => insertions += 1,
+                        Line::Delete(_) => deletions += 1,
+                        Line::Context(_) => {}
+                    }
+                }
+            }
+
+            writeln!(
+                writer,
+                "  {name:<width$}  {accepted} accepted, {rejected} rejected  {} {}",
+                self.diff_style(Color::Green)
+                    .paint(format!("+{insertions}")),
+                self.diff_style(Color::Red).paint(format!("-{deletions}")),
+            )?;
+        }
+
+        Ok(writer.take_lineno())
+    }
+
+    /// Reads a line of input, or `None` at the end of input.
+    fn ask_line(&mut self, msg: &str) -> Result<Option<String>> {
+        let style = self.options.styles.prompt;
+        write!(self.stdout, "{}", style.paint(msg))?;
+        self.stdout.flush()?;
+
+        let mut line = String::new();
+        if BufRead::read_line(&mut self.stdin.lock(), &mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim_end_matches('\n').to_owned()))
+    }
+
+    /// Lists every hunk of every file and asks which one to go to.
+    fn goto(
+        &mut self,
+        changes: &Changes,
+        hunks: &[ListedHunk],
+        resolutions: &[Vec<Option<bool>>],
+    ) -> Result<Option<Step>> {
+        // like git, the list is shown a page at a time, which has to fit on the screen
+        let size = self.term_size()?;
+        let max_lines = size.1.saturating_sub(2);
+        let mut start = 0;
+        let answer = loop {
+            let (mut end, mut lines) = (start, 0);
+            {
+                let mut writer = CountLines::new(self.stdout.lock(), size.0);
+                while end < hunks.len() && (end == start || lines + 2 <= max_lines) {
+                    let listed = &hunks[end];
+                    if end == start || hunks[end - 1].step.change != listed.step.change {
+                        let change = &changes.changes[listed.step.change];
+                        let name = changes.modified_name(change.modified());
+                        writeln!(writer, "{}", name.display())?;
+                    }
+                    let marker = match resolutions[listed.step.change].get(listed.step.hunk) {
+                        Some(Some(true)) => '+',
+                        Some(Some(false)) => '-',
+                        _ => ' ',
+                    };
+                    writeln!(writer, "{:>4}{marker}: {}", end + 1, listed.summary)?;
+                    lines += writer.take_lineno();
+                    end += 1;
+                }
+            }
+
+            let more = end < hunks.len();
+            let prompt = match more {
+                true => "Go to which hunk (<ret> to see more)? ",
+                false => "Go to which hunk? ",
+            };
+            let answer = self.ask_line(prompt)?;
+            lines += 1;
+            if more
+                && answer
+                    .as_deref()
+                    .is_some_and(|answer| answer.trim().is_empty())
+            {
+                self.erase_previous_lines(lines)?;
+                start = end;
+                continue;
+            }
+            self.inline_uncleared_lines.1 += lines;
+            break answer;
+        };
+
+        let Some(answer) = answer else {
+            return Ok(None);
+        };
+        let answer = answer.trim();
+        if answer.is_empty() {
+            return Ok(None);
+        }
+        match answer
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| hunks.get(n.checked_sub(1)?))
+        {
+            Some(listed) => Ok(Some(listed.step)),
+            None => {
+                self.write_error(&format!("Invalid number: '{answer}'"))?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Asks for a regex and finds the next hunk after `from` with a matching line.
+    fn search(&mut self, hunks: &[ListedHunk], from: Step) -> Result<Option<Step>> {
+        let Some(pattern) = self.ask_line("Search for regex? ")? else {
+            return Ok(None);
+        };
+        self.inline_uncleared_lines.1 += 1;
+        if pattern.is_empty() {
+            return Ok(None);
+        }
+
+        let regex = match Regex::new(&pattern) {
+            Ok(regex) => regex,
+            Err(err) => {
+                self.write_error(&format!("Malformed search regexp {pattern}: {err}"))?;
+                return Ok(None);
+            }
+        };
+
+        let current = hunks
+            .iter()
+            .position(|listed| listed.step == from)
+            .unwrap_or(0);
+        let (before, after) = hunks.split_at(current + 1);
+        let found = (after.iter().chain(before))
+            .find(|listed| listed.text.lines().any(|line| regex.is_match(line)));
+        match found {
+            Some(listed) => Ok(Some(listed.step)),
+            None => {
+                self.write_error("No hunk matches the given pattern")?;
+                Ok(None)
+            }
+        }
+    }
+
     fn edit(&self, hunk: &str) -> Result<String> {
-        let msg = format!("{EDIT_HUNK_HEADER}\n{hunk}{EDIT_HUNK_TRAILER}");
+        // like git, reversed hunks keep their `+` lines, which are on the original side
+        let (context, delete) = match self.options.reversed {
+            true => ("+", "-"),
+            false => ("-", "+"),
+        };
+        let trailer = EDIT_HUNK_TRAILER
+            .replace("{context}", context)
+            .replace("{delete}", delete);
+        let msg = format!("{EDIT_HUNK_HEADER}\n{hunk}{trailer}");
         let path = hunk_edit_path(&std::env::current_dir()?);
         std::fs::write(&path, msg)?;
 
-        let mut cmd = Command::new("nvim").arg(&path).spawn()?;
-        let status = cmd.wait()?;
-        ensure!(status.success(), "Error running external editor");
+        let cwd = std::env::current_dir()?;
+        let editor = editor::resolve(self.options.editor.as_deref(), &cwd);
+        let status = editor::command(&editor, &path)
+            .status()
+            .with_context(|| format!("Could not run editor '{editor}'"))?;
+        ensure!(status.success(), "Error running external editor '{editor}'");
 
         let edited = std::fs::read_to_string(path)?;
         let without_comments = edited
@@ -468,24 +1184,15 @@ impl DiffPatch {
 }
 
 fn hunk_edit_path(cwd: &Path) -> PathBuf {
-    let vcs_dir = iter::successors(Some(cwd), |path| path.parent()).find_map(|dir| {
-        let jj_dir = dir.join(".jj");
-        if jj_dir.is_dir() {
-            Some(jj_dir)
-        } else {
-            let git_dir = dir.join(".git");
-            git_dir.is_dir().then_some(git_dir)
-        }
-    });
-    let dir = vcs_dir.unwrap_or_else(std::env::temp_dir);
+    let dir = editor::find_vcs_dir(cwd).unwrap_or_else(std::env::temp_dir);
 
     dir.join("addp-hunk-edit.diff")
 }
 
 const EDIT_HUNK_HEADER: &str = "# Manual hunk edit mode -- see bottom for a quick guide.";
 const EDIT_HUNK_TRAILER: &str = "# ---
-# To remove '-' lines, make them ' ' lines (context).
-# To remove '+' lines, delete them.
+# To remove '{context}' lines, make them ' ' lines (context).
+# To remove '{delete}' lines, delete them.
 # Lines starting with # will be removed.
 # If the patch applies cleanly, the edited hunk will immediately be marked for staging.
 # If it does not apply cleanly, you will be given an opportunity to
@@ -493,7 +1200,74 @@ const EDIT_HUNK_TRAILER: &str = "# ---
 # aborted and the hunk is left unchanged.
 ";
 
-enum Action {
+/// A change to a file's metadata, prompted for before its content hunks.
+pub enum MetaHunk {
+    Rename(PathBuf, PathBuf),
+    Copy(PathBuf, PathBuf),
+    Mode(u32, u32),
+}
+
+impl MetaHunk {
+    fn kind(&self) -> &'static str {
+        match self {
+            MetaHunk::Rename(..) => "rename",
+            MetaHunk::Copy(..) => "copy",
+            MetaHunk::Mode(..) => "mode change",
+        }
+    }
+
+    fn write_into(&self, mut w: impl Write, style: Style, reversed: bool) -> std::io::Result<()> {
+        match self {
+            MetaHunk::Rename(from, to) | MetaHunk::Copy(from, to) => {
+                let (from, to) = match reversed {
+                    true => (to, from),
+                    false => (from, to),
+                };
+                let kind = self.kind();
+                writeln!(
+                    w,
+                    "{}",
+                    style.paint(format!("{kind} from {}", from.display()))
+                )?;
+                writeln!(w, "{}", style.paint(format!("{kind} to {}", to.display())))?;
+            }
+            &MetaHunk::Mode(original, modified) => {
+                let (old, new) = match reversed {
+                    true => (modified, original),
+                    false => (original, modified),
+                };
+                writeln!(w, "{}", style.paint(format!("old mode {old:o}")))?;
+                writeln!(w, "{}", style.paint(format!("new mode {new:o}")))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The number of prompts for a file: its meta hunks followed by its content hunks.
+/// Contents that changed without any diffable hunks (binary or empty files) get a single prompt.
+fn n_logical_hunks(
+    change: &ChangeKind,
+    (original, modified): &(Content, Content),
+    metas: &[MetaHunk],
+    n_hunks: usize,
+) -> usize {
+    let content_changed = !matches!(
+        change,
+        ChangeKind::Modified(_) | ChangeKind::Renamed(..) | ChangeKind::Copied(..)
+    ) || original.as_bytes() != modified.as_bytes();
+    let n_content_hunks = match content_changed {
+        true => n_hunks.max(1),
+        false => n_hunks,
+    };
+    metas.len() + n_content_hunks
+}
+
+/// A key, the action it triggers and its description in the help.
+type Binding = (char, Action, &'static str);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
     HunkYes,
     HunkNo,
     FileYes,
@@ -503,6 +1277,14 @@ enum Action {
     Quit,
     Prev,
     Next,
+    PrevUndecided,
+    NextUndecided,
+    SelectLines,
+    Undo,
+    Goto,
+    Search,
+    Instructions,
+    Help,
 
     Clear,
     Exit,
@@ -510,21 +1292,115 @@ enum Action {
 }
 
 impl Action {
-    fn from_char(c: char) -> Option<Action> {
-        Some(match c {
-            'y' => Action::HunkYes,
-            'n' => Action::HunkNo,
-            'a' => Action::FileYes,
-            'd' => Action::FileNo,
-            's' => Action::Split,
-            'e' => Action::Edit,
-            'q' => Action::Quit,
-            'l' => Action::Clear,
+    /// Every action with a key, in the order they are advertised in the prompt.
+    /// `{verb}` is replaced by what accepting a hunk means, e.g. "stage".
+    const KEYS: &[Binding] = &[
+        ('y', Action::HunkYes, "{verb} this hunk"),
+        ('n', Action::HunkNo, "do not {verb} this hunk"),
+        (
+            'q',
+            Action::Quit,
+            "quit; do not {verb} this hunk or any of the remaining ones",
+        ),
+        ('a', Action::FileYes, "{verb} all hunks in this file"),
+        ('d', Action::FileNo, "do not {verb} any hunk in this file"),
+        (
+            'j',
+            Action::NextUndecided,
+            "leave this hunk undecided, see next undecided hunk",
+        ),
+        (
+            'J',
+            Action::Next,
+            "leave this hunk undecided, see next hunk",
+        ),
+        (
+            'k',
+            Action::PrevUndecided,
+            "leave this hunk undecided, see previous undecided hunk",
+        ),
+        (
+            'K',
+            Action::Prev,
+            "leave this hunk undecided, see previous hunk",
+        ),
+        ('g', Action::Goto, "select a hunk to go to"),
+        (
+            '/',
+            Action::Search,
+            "search for a hunk matching the given regex",
+        ),
+        (
+            's',
+            Action::Split,
+            "split the current hunk into smaller hunks",
+        ),
+        ('e', Action::Edit, "manually edit the current hunk"),
+        (
+            'v',
+            Action::SelectLines,
+            "select individual lines of the current hunk",
+        ),
+        ('u', Action::Undo, "undo the last decision"),
+        ('l', Action::Clear, "redraw the screen, also ^L"),
+        ('i', Action::Instructions, "show the instructions from jj"),
+        ('?', Action::Help, "print help"),
+    ];
+    /// Keys that aren't characters, only listed in the help.
+    const OTHER_KEYS: &[(&str, &str)] = &[
+        ("←/↑", "go to the previous hunk"),
+        ("→/↓", "go to the next hunk"),
+        ("^C", "exit without applying any changes"),
+    ];
+
+    /// Looks up an action by the name it is rebound with in the config, e.g. `split`.
+    pub fn from_name(name: &str) -> Option<Action> {
+        let action = match name {
+            "yes" => Action::HunkYes,
+            "no" => Action::HunkNo,
+            "quit" => Action::Quit,
+            "all" => Action::FileYes,
+            "none" => Action::FileNo,
+            "next-undecided" => Action::NextUndecided,
+            "next" => Action::Next,
+            "prev-undecided" => Action::PrevUndecided,
+            "prev" => Action::Prev,
+            "goto" => Action::Goto,
+            "search" => Action::Search,
+            "split" => Action::Split,
+            "edit" => Action::Edit,
+            "select-lines" => Action::SelectLines,
+            "undo" => Action::Undo,
+            "redraw" => Action::Clear,
+            "instructions" => Action::Instructions,
+            "help" => Action::Help,
             _ => return None,
-        })
+        };
+        Some(action)
+    }
+
+    fn from_char(bindings: &[Binding], c: char) -> Option<Action> {
+        let (_, action, _) = bindings.iter().find(|(key, ..)| *key == c)?;
+        Some(*action)
+    }
+
+    fn help(bindings: &[Binding], verb: &str) -> String {
+        let keys = bindings
+            .iter()
+            .map(|(key, _, description)| (key.to_string(), description));
+        let other_keys = Action::OTHER_KEYS
+            .iter()
+            .map(|(key, description)| (key.to_string(), description));
+
+        let mut help = String::new();
+        for (key, description) in keys.chain(other_keys) {
+            let description = description.replace("{verb}", verb);
+            help.push_str(&format!("{key} - {description}\n"));
+        }
+        help
     }
 
-    fn from_str(s: &str) -> Option<Action> {
+    fn from_str(bindings: &[Binding], s: &str) -> Option<Action> {
         match s {
             "\x1b[D" | "\x1b[A" => Some(Action::Prev),
             "\x1b[C" | "\x1b[B" => Some(Action::Next),
@@ -534,7 +1410,7 @@ impl Action {
                 if chars.next().is_some() {
                     return None;
                 }
-                Action::from_char(c)
+                Action::from_char(bindings, c)
             }
         }
     }
@@ -542,64 +1418,287 @@ impl Action {
 
 fn write_header(
     mut w: impl Write,
+    style: Style,
     filename_original: Option<&Path>,
     filename_modified: Option<&Path>,
 ) -> std::io::Result<()> {
-    let has_color = true;
-    let style = Style::new().fg(Color::White).bold();
-
-    if has_color {
-        write!(w, "{}", style.prefix())?;
-    }
+    write!(w, "{}", style.prefix())?;
     if let Some(original) = filename_original {
         writeln!(w, "--- {}", original.display())?;
     }
     if let Some(modified) = filename_modified {
         writeln!(w, "+++ {}", modified.display())?;
     }
-    if has_color {
-        write!(w, "{}", style.suffix())?;
-    }
+    write!(w, "{}", style.suffix())?;
 
     Ok(())
 }
 
-fn apply_change(
-    changes: &Changes,
+/// The pseudo-hunk shown in place of a diff when either side is binary.
+fn binary_summary(
     change: &ChangeKind,
-    original: &str,
-    patch: &Patch<str>,
-    file_resolution: &[bool],
-) -> Result<()> {
-    let applied = diffy::apply(original, patch)
-        .with_context(|| format!("Could not apply patch\n{}", patch.to_string()))?;
+    (original, modified): &(Content, Content),
+    reversed: bool,
+) -> Option<String> {
+    if !original.is_binary() && !modified.is_binary() {
+        return None;
+    }
 
-    let original_path = changes.original_path(change.inner());
-    let modified_path = changes.modified_path(change.inner());
-    match change {
-        ChangeKind::Modified(_) => {
-            std::fs::write(&modified_path, applied).context("error applying file modification")?
+    let original_size = format_size(original.as_bytes().len());
+    let modified_size = format_size(modified.as_bytes().len());
+    Some(match (reversed, change) {
+        (false, ChangeKind::Added(_)) | (true, ChangeKind::Removed(_)) => {
+            format!("binary file added, {modified_size}")
+        }
+        (false, ChangeKind::Removed(_)) | (true, ChangeKind::Added(_)) => {
+            format!("binary file removed, {original_size}")
         }
-        ChangeKind::Removed(_) => {
-            assert_eq!(file_resolution.len(), 1);
-            let resolution = file_resolution[0];
+        (false, _) => format!("binary file changed, {original_size} -> {modified_size}"),
+        (true, _) => format!("binary file changed, {modified_size} -> {original_size}"),
+    })
+}
+
+#[test]
+fn check_format_size() {
+    assert_eq!(format_size(0), "0 B");
+    assert_eq!(format_size(1023), "1023 B");
+    assert_eq!(format_size(12595), "12.3 KiB");
+    assert_eq!(format_size(3 * 1024 * 1024), "3.0 MiB");
+}
+fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = None;
+    for next_unit in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = Some(next_unit);
+    }
+
+    match unit {
+        Some(unit) => format!("{size:.1} {unit}"),
+        None => format!("{bytes} B"),
+    }
+}
 
-            if resolution == false {
-                std::fs::copy(&original_path, &modified_path)
-                    .context("error applying file removal")?;
+/// Formats a hunk keeping only the `selected` changes.
+/// Deselected deletions turn into context, deselected insertions are dropped.
+fn partial_hunk(hunk: &Hunk<'_, str>, selected: &[bool], reversed: bool) -> String {
+    // unselected lines of the original side stay, which are the `+` lines when reversed
+    let kept: Vec<(char, &str)> = (hunk.lines().iter().zip(selected))
+        .filter_map(|(line, &selected)| match *line {
+            Line::Context(text) => Some((' ', text)),
+            Line::Delete(text) if selected => Some(('-', text)),
+            Line::Delete(text) if !reversed => Some((' ', text)),
+            Line::Insert(text) if selected => Some(('+', text)),
+            Line::Insert(text) if reversed => Some((' ', text)),
+            Line::Delete(_) | Line::Insert(_) => None,
+        })
+        .collect();
+    let old_last = kept.iter().rposition(|&(sign, _)| sign != '+');
+    let new_last = kept.iter().rposition(|&(sign, _)| sign != '-');
+
+    let mut lines = String::new();
+    let (mut old_len, mut new_len) = (0, 0);
+    let mut push = |sign, text: &str| {
+        lines.push(sign);
+        lines.push_str(text);
+        if !text.ends_with('\n') {
+            lines.push_str("\n\\ No newline at end of file\n");
+        }
+    };
+    for (i, &(sign, text)) in kept.iter().enumerate() {
+        old_len += (sign != '+') as usize;
+        new_len += (sign != '-') as usize;
+        // a line without a newline that is no longer the last of its side gets one, and a
+        // context line that is last on only one side becomes a deletion and an insertion
+        let with_newline = |last| match text.ends_with('\n') || last {
+            true => Cow::Borrowed(text),
+            false => Cow::Owned(format!("{text}\n")),
+        };
+        let (old_text, new_text) = (
+            with_newline(old_last == Some(i)),
+            with_newline(new_last == Some(i)),
+        );
+        match sign {
+            ' ' if old_text != new_text => {
+                push('-', &old_text);
+                push('+', &new_text);
             }
+            '+' => push(sign, &new_text),
+            _ => push(sign, &old_text),
         }
-        ChangeKind::Added(_) => {
-            assert_eq!(file_resolution.len(), 1);
+    }
+
+    let old_start = hunk.old_range().start();
+    let new_start = hunk.new_range().start();
+    format!("@@ -{old_start},{old_len} +{new_start},{new_len} @@\n{lines}")
+}
+
+#[test]
+fn check_partial_hunk() {
+    let hunk = Hunk::from_str("@@ -1,3 +1,3 @@\n a\n-b\n-c\n+d\n+e\n", true).unwrap();
+    assert_eq!(
+        partial_hunk(&hunk, &[true, false, true, true, false], false),
+        "@@ -1,3 +1,3 @@\n a\n b\n-c\n+d\n"
+    );
+    assert_eq!(
+        partial_hunk(&hunk, &[true, false, true, true, false], true),
+        "@@ -1,3 +1,3 @@\n a\n-c\n+d\n e\n"
+    );
+
+    // only the last line of each side can lack a newline
+    let hunk = Hunk::from_str(
+        "@@ -1 +1 @@\n-a\n\\ No newline at end of file\n+b\n\\ No newline at end of file\n",
+        true,
+    )
+    .unwrap();
+    assert_eq!(
+        partial_hunk(&hunk, &[false, true], false),
+        "@@ -1,1 +1,2 @@\n-a\n\\ No newline at end of file\n+a\n+b\n\\ No newline at end of file\n"
+    );
+}
 
-            let resolution = file_resolution[0];
-            if resolution == false {
-                std::fs::remove_file(modified_path).context("error applying file addition")?;
+/// Whether the hunk at `index` of `patch` still applies to `original` when replaced by `hunk`.
+fn hunk_applies(
+    original: &str,
+    patch: &Patch<'_, str>,
+    index: usize,
+    hunk: &Hunk<'_, str>,
+) -> bool {
+    let mut single_hunk = patch.clone();
+    for (i, other) in single_hunk.hunks_mut().iter_mut().enumerate() {
+        *other = match i == index {
+            true => hunk.clone(),
+            false => Hunk::default(),
+        };
+    }
+    diffy::apply(original, &single_hunk).is_ok()
+}
+
+/// Parses a selection like `1,3-5` of 1-based line numbers into 0-based indices.
+fn parse_line_selection(selection: &str, n_lines: usize) -> Option<Vec<usize>> {
+    let mut numbers = Vec::new();
+    for part in selection.split(',') {
+        let (start, end): (usize, usize) = match part.trim().split_once('-') {
+            Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+            None => {
+                let n = part.trim().parse().ok()?;
+                (n, n)
             }
+        };
+        if start == 0 || start > end || end > n_lines {
+            return None;
         }
+        numbers.extend(start - 1..end);
     }
+    Some(numbers)
+}
 
-    Ok(())
+#[test]
+fn check_find_undecided() {
+    let step = |change, hunk| Step { change, hunk };
+    let resolutions = [
+        vec![None, Some(true)],
+        vec![Some(false)],
+        vec![Some(true), None],
+    ];
+    assert_eq!(
+        find_undecided(&resolutions, step(0, 0), true),
+        Some(step(2, 1))
+    );
+    assert_eq!(
+        find_undecided(&resolutions, step(2, 1), true),
+        Some(step(0, 0))
+    );
+    assert_eq!(
+        find_undecided(&resolutions, step(1, 0), false),
+        Some(step(0, 0))
+    );
+    assert_eq!(
+        find_undecided(&resolutions, step(0, 0), false),
+        Some(step(2, 1))
+    );
+    assert_eq!(find_undecided(&[vec![Some(true)]], step(0, 0), true), None);
+}
+/// Finds the closest undecided hunk after `from`, or before it if not `forward`, wrapping around.
+/// Returns `from` itself if it is the only undecided hunk.
+fn find_undecided(resolutions: &[Vec<Option<bool>>], from: Step, forward: bool) -> Option<Step> {
+    let steps: Vec<Step> = (resolutions.iter().enumerate())
+        .flat_map(|(change, file)| (0..file.len()).map(move |hunk| Step { change, hunk }))
+        .collect();
+    let n = steps.len();
+    let current = steps.iter().position(|&step| step == from)?;
+
+    (1..=n)
+        .map(|offset| match forward {
+            true => steps[(current + offset) % n],
+            false => steps[(current + n - offset) % n],
+        })
+        .find(|step| resolutions[step.change][step.hunk].is_none())
+}
+
+/// A logical hunk as listed by [`Action::Goto`] and searched by [`Action::Search`].
+struct ListedHunk {
+    step: Step,
+    summary: String,
+    /// The changed and context lines, empty for meta and binary hunks.
+    text: String,
+}
+
+fn list_hunks(
+    changes: &Changes,
+    contents: &[(Content, Content)],
+    all_metas: &[Vec<MetaHunk>],
+    patches: &[Patch<'_, str>],
+    reversed: bool,
+) -> Vec<ListedHunk> {
+    let mut hunks = Vec::new();
+    for (change_index, (((change, content), metas), patch)) in
+        (changes.iter().zip(contents).zip(all_metas).zip(patches)).enumerate()
+    {
+        let n_hunks_logical = n_logical_hunks(change, content, metas, patch.hunks().len());
+        for hunk_index in 0..n_hunks_logical {
+            let step = Step {
+                change: change_index,
+                hunk: hunk_index,
+            };
+            let (summary, text) = match hunk_index.checked_sub(metas.len()) {
+                None => (metas[hunk_index].kind().to_owned(), String::new()),
+                Some(i) => match patch.hunks().get(i) {
+                    Some(hunk) => {
+                        let hunk = reverse_if(hunk, reversed);
+                        let text = PatchFormatter::new().fmt_hunk(&hunk).to_string();
+                        let first_change = (text.lines().skip(1))
+                            .find(|line| !line.starts_with(' '))
+                            .unwrap_or_default();
+                        let summary = format!(
+                            "-{} +{}  {first_change}",
+                            hunk.old_range(),
+                            hunk.new_range()
+                        );
+                        (summary, text)
+                    }
+                    None => {
+                        let summary = binary_summary(change, content, reversed);
+                        (
+                            summary.unwrap_or_else(|| "empty file".to_owned()),
+                            String::new(),
+                        )
+                    }
+                },
+            };
+            hunks.push(ListedHunk {
+                step,
+                summary,
+                text,
+            });
+        }
+    }
+    hunks
 }
 
 fn reverse_if<'h, 'c>(hunk: &'c Hunk<'h, str>, reverse: bool) -> Cow<'c, Hunk<'h, str>> {
@@ -609,6 +1708,13 @@ fn reverse_if<'h, 'c>(hunk: &'c Hunk<'h, str>, reverse: bool) -> Cow<'c, Hunk<'h
     }
 }
 
+/// What [`DiffPatch::keys`] reads from the terminal.
+enum Input {
+    Key(Key),
+    /// The terminal was resized and needs to be redrawn.
+    Resize,
+}
+
 enum MaybeRawTerminal<W: Write + AsFd> {
     Raw(RawTerminal<W>),
     Normal(W),
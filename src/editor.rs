@@ -0,0 +1,89 @@
+use std::ffi::OsStr;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Characters that make git run the editor through `sh` instead of executing it directly.
+const SHELL_META: &str = "|&;<>()$`\\\"' \t\n*?[#~=%";
+
+/// Finds the closest `.jj` or `.git` directory above `cwd`.
+pub fn find_vcs_dir(cwd: &Path) -> Option<PathBuf> {
+    iter::successors(Some(cwd), |path| path.parent()).find_map(|dir| {
+        let jj_dir = dir.join(".jj");
+        if jj_dir.is_dir() {
+            Some(jj_dir)
+        } else {
+            let git_dir = dir.join(".git");
+            git_dir.is_dir().then_some(git_dir)
+        }
+    })
+}
+
+/// Resolves the editor like git does: `configured` (i.e. `DIFFPATCH_EDITOR`), then the editor
+/// of the surrounding jj or git repo, then `VISUAL`, `EDITOR` and finally `vi`.
+pub fn resolve(configured: Option<&str>, cwd: &Path) -> String {
+    let vcs_editor = || {
+        let vcs_dir = find_vcs_dir(cwd)?;
+        match vcs_dir.file_name() == Some(OsStr::new(".jj")) {
+            true => command_output("jj", &["config", "get", "ui.editor"], cwd),
+            false => command_output("git", &["var", "GIT_EDITOR"], cwd),
+        }
+    };
+
+    configured
+        .filter(|editor| !editor.trim().is_empty())
+        .map(str::to_owned)
+        .or_else(vcs_editor)
+        .or_else(|| env_editor("VISUAL"))
+        .or_else(|| env_editor("EDITOR"))
+        .unwrap_or_else(|| "vi".to_owned())
+}
+
+fn env_editor(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+}
+
+fn command_output(program: &str, args: &[&str], cwd: &Path) -> Option<String> {
+    let output = Command::new(program)
+        .args(args)
+        .current_dir(cwd)
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    let editor = stdout.trim();
+    (output.status.success() && !editor.is_empty()).then(|| editor.to_owned())
+}
+
+#[test]
+fn check_command() {
+    let args = |cmd: &Command| {
+        iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|arg| arg.to_str().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+    let path = Path::new("hunk.diff");
+    assert_eq!(args(&command("hx", path)), ["hx", "hunk.diff"]);
+    assert_eq!(
+        args(&command("emacs -nw", path)),
+        ["sh", "-c", "emacs -nw \"$@\"", "emacs -nw", "hunk.diff"]
+    );
+}
+/// Builds the command that opens `path` in `editor`. Like git, editors containing arguments or
+/// quoting are run through `sh`.
+pub fn command(editor: &str, path: &Path) -> Command {
+    if editor.contains(|c| SHELL_META.contains(c)) {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(format!("{editor} \"$@\""))
+            .arg(editor)
+            .arg(path);
+        cmd
+    } else {
+        let mut cmd = Command::new(editor);
+        cmd.arg(path);
+        cmd
+    }
+}
@@ -0,0 +1,337 @@
+use color_eyre::Result;
+use color_eyre::eyre::{Context, ContextCompat, bail, ensure};
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::apply::{self, Entry};
+use crate::changes::FileType;
+use crate::config::ParseEnumError;
+
+/// The git commands whose `--patch` mode diffpatch can replace.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GitCommand {
+    /// Stage changes from the worktree into the index.
+    Add,
+    /// Unstage changes from the index, i.e. take them from `HEAD` again.
+    Reset,
+    /// Discard changes in the worktree, i.e. take them from the index again.
+    Checkout,
+}
+
+impl GitCommand {
+    /// Parses `add`, `reset` or `checkout`, or `restore` which is `reset` when `staged`.
+    pub fn parse(command: &str, staged: bool) -> Result<GitCommand> {
+        let parsed = match command {
+            "add" => GitCommand::Add,
+            "reset" => GitCommand::Reset,
+            "checkout" => GitCommand::Checkout,
+            "restore" if staged => GitCommand::Reset,
+            "restore" => GitCommand::Checkout,
+            other => {
+                let possible = &["add", "reset", "checkout", "restore"];
+                return Err(ParseEnumError(possible, other.to_owned()))
+                    .context("unknown git command");
+            }
+        };
+        ensure!(
+            command == "restore" || !staged,
+            "--staged only works with git restore"
+        );
+        Ok(parsed)
+    }
+}
+
+/// Where one side of a changed path comes from.
+enum Side {
+    Absent,
+    /// A blob in the object database, with its mode.
+    Blob(u32, String),
+    /// The file in the worktree, with the mode git sees, which differs from the actual one with
+    /// `core.fileMode=false`.
+    Worktree(u32),
+}
+
+/// Both sides of a `git diff`, copied into a temporary dir so that they can be diffed like any
+/// other dirs. The dir is removed again when this is dropped.
+pub struct GitDiff {
+    command: GitCommand,
+    /// The root of the worktree.
+    root: PathBuf,
+    dir: PathBuf,
+    /// Every path listed by `git diff`, relative to `root`, with its original side.
+    paths: Vec<(PathBuf, Option<Entry>)>,
+}
+
+#[test]
+fn check_git_add() {
+    let dir = std::env::temp_dir().join(format!("diffpatch-git-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let run = |args: &[&str]| git(&dir, args, None).unwrap();
+    run(&["init", "-q"]);
+    std::fs::write(dir.join("a"), "a\n").unwrap();
+    std::fs::write(dir.join("b"), "b\n").unwrap();
+    run(&["add", "a", "b"]);
+    std::fs::write(dir.join("a"), "a\nchanged\n").unwrap();
+    std::fs::write(dir.join("b"), "b\nchanged\n").unwrap();
+    std::fs::write(dir.join("c"), "c\n").unwrap();
+
+    let diff = GitDiff::prepare(GitCommand::Add, &dir, &[]).unwrap();
+    // untracked files aren't offered, like with `git add -p`
+    assert_eq!(diff.paths.len(), 2);
+    // accept the change of `a` only
+    let accepted = apply::read_entry(&diff.modified_dir().join("a")).unwrap();
+    apply::write_entry(&diff.output_dir().join("a"), &accepted.unwrap()).unwrap();
+    diff.update_index().unwrap();
+    assert_eq!(run(&["show", ":a"]), b"a\nchanged\n");
+    assert_eq!(run(&["show", ":b"]), b"b\n");
+
+    drop(diff);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+impl GitDiff {
+    /// Copies the changes `command` would offer for `pathspecs`, which are relative to `cwd`.
+    pub fn prepare(command: GitCommand, cwd: &Path, pathspecs: &[PathBuf]) -> Result<GitDiff> {
+        let root = git(cwd, &["rev-parse", "--show-toplevel"], None)?;
+        // signals and panics exit without dropping, so this can be left behind
+        let dir = std::env::temp_dir().join(format!("diffpatch-{}", std::process::id()));
+        std::fs::create_dir(&dir)
+            .with_context(|| format!("could not create '{}'", dir.display()))?;
+        let mut diff = GitDiff {
+            command,
+            root: PathBuf::from(OsStr::from_bytes(root.trim_ascii())),
+            dir,
+            paths: Vec::new(),
+        };
+
+        let mut args: Vec<OsString> = ["diff", "--raw", "-z", "--no-renames", "--no-relative"]
+            .map(OsString::from)
+            .to_vec();
+        if command == GitCommand::Reset {
+            args.push("--cached".into());
+        }
+        args.push("--".into());
+        args.extend(pathspecs.iter().map(|path| path.as_os_str().to_owned()));
+        let raw = git(cwd, &args, None)?;
+
+        // every path is preceded by `:<old mode> <new mode> <old sha> <new sha> <status>`
+        let mut records = raw
+            .split(|&b| b == b'\0')
+            .filter(|record| !record.is_empty());
+        while let Some(record) = records.next() {
+            let record = std::str::from_utf8(record)?;
+            let path = records.next().context("missing path in git diff")?;
+            let path = PathBuf::from(OsStr::from_bytes(path));
+            let fields: Vec<&str> = record.trim_start_matches(':').split(' ').collect();
+            let &[old_mode, new_mode, old_sha, new_sha, status] = fields.as_slice() else {
+                bail!("unexpected output of git diff '{record}'");
+            };
+            ensure!(
+                status != "U",
+                "'{}' has conflicts, resolve them first",
+                path.display()
+            );
+            let (old_mode, new_mode) = (parse_mode(old_mode)?, parse_mode(new_mode)?);
+            // submodules are not supported
+            if old_mode == 0o160000 || new_mode == 0o160000 {
+                continue;
+            }
+
+            let blob = |mode, sha: &str| match mode {
+                0 => Side::Absent,
+                _ => Side::Blob(mode, sha.to_owned()),
+            };
+            let worktree = match new_mode {
+                0 => Side::Absent,
+                _ => Side::Worktree(new_mode),
+            };
+            let (original, modified) = match command {
+                GitCommand::Add => (blob(old_mode, old_sha), worktree),
+                GitCommand::Reset => (blob(new_mode, new_sha), blob(old_mode, old_sha)),
+                GitCommand::Checkout => (worktree, blob(old_mode, old_sha)),
+            };
+            diff.copy_side(&original, &path, &diff.original_dir())?;
+            diff.copy_side(&modified, &path, &diff.modified_dir())?;
+            let original = apply::read_entry(&diff.original_dir().join(&path))?;
+            diff.paths.push((path, original));
+        }
+
+        // both sides must exist to be diffed as dirs
+        for dir in [diff.original_dir(), diff.modified_dir()] {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(diff)
+    }
+
+    pub fn original_dir(&self) -> PathBuf {
+        self.dir.join("original")
+    }
+    pub fn modified_dir(&self) -> PathBuf {
+        self.dir.join("modified")
+    }
+    /// `checkout` writes to the worktree directly, otherwise the results are written over the
+    /// original side to be staged by [`GitDiff::update_index`].
+    pub fn output_dir(&self) -> PathBuf {
+        match self.command {
+            GitCommand::Checkout => self.root.clone(),
+            GitCommand::Add | GitCommand::Reset => self.original_dir(),
+        }
+    }
+
+    /// Copies one side of `path` to `dir`. Both sides are compared like git does, so blobs are
+    /// smudged to be compared with the worktree, and the worktree is cleaned to be compared with
+    /// blobs.
+    fn copy_side(&self, side: &Side, path: &Path, dir: &Path) -> Result<()> {
+        let path_arg = path_arg(path);
+        let entry = match side {
+            Side::Absent => return Ok(()),
+            Side::Blob(mode, sha) => {
+                let file_type = match mode & 0o170000 == 0o120000 {
+                    true => FileType::Symlink,
+                    false => FileType::File,
+                };
+                let content = match (self.command, file_type) {
+                    (GitCommand::Checkout, FileType::File) => {
+                        let cat_file: [&OsStr; 4] = [
+                            "cat-file".as_ref(),
+                            "--filters".as_ref(),
+                            &path_arg,
+                            sha.as_ref(),
+                        ];
+                        git(&self.root, &cat_file, None)?
+                    }
+                    _ => git(&self.root, &["cat-file", "blob", sha], None)?,
+                };
+                Entry {
+                    file_type,
+                    content,
+                    mode: *mode,
+                }
+            }
+            Side::Worktree(mode) => {
+                let worktree_path = self.root.join(path);
+                let mut entry = apply::read_entry(&worktree_path)?
+                    .with_context(|| format!("'{}' does not exist", worktree_path.display()))?;
+                if entry.file_type == FileType::File {
+                    entry.mode = *mode;
+                }
+                if self.command == GitCommand::Add && entry.file_type == FileType::File {
+                    let hash_object: [&OsStr; 4] = [
+                        "hash-object".as_ref(),
+                        "-w".as_ref(),
+                        &path_arg,
+                        "--stdin".as_ref(),
+                    ];
+                    let sha = git(&self.root, &hash_object, Some(&entry.content))?;
+                    let sha = String::from_utf8_lossy(sha.trim_ascii()).into_owned();
+                    entry.content = git(&self.root, &["cat-file", "blob", &sha], None)?;
+                }
+                entry
+            }
+        };
+        let path = dir.join(path);
+        apply::write_entry(&path, &entry)
+            .with_context(|| format!("could not write '{}'", path.display()))
+    }
+
+    /// Writes the paths which changed in the output dir to the index, so this does nothing if
+    /// no changes were written.
+    pub fn update_index(&self) -> Result<()> {
+        if self.command == GitCommand::Checkout {
+            return Ok(());
+        }
+
+        let (mut index_info, mut removed) = (Vec::new(), Vec::new());
+        for (path, original) in &self.paths {
+            let entry = apply::read_entry(&self.output_dir().join(path))?;
+            if entry == *original {
+                continue;
+            }
+            let Some(entry) = entry else {
+                removed.extend_from_slice(path.as_os_str().as_bytes());
+                removed.push(b'\0');
+                continue;
+            };
+            // symlinks are never filtered by git
+            let filters = match entry.file_type {
+                FileType::File => path_arg(path),
+                FileType::Symlink => OsString::from("--no-filters"),
+            };
+            let hash_object: [&OsStr; 4] = [
+                "hash-object".as_ref(),
+                "-w".as_ref(),
+                &filters,
+                "--stdin".as_ref(),
+            ];
+            let sha = git(&self.root, &hash_object, Some(&entry.content))?;
+            let mode = match entry.file_type {
+                FileType::Symlink => 0o120000,
+                FileType::File if entry.mode & 0o111 != 0 => 0o100755,
+                FileType::File => 0o100644,
+            };
+            write!(
+                index_info,
+                "{mode:o} {}\t",
+                String::from_utf8_lossy(sha.trim_ascii())
+            )?;
+            index_info.extend_from_slice(path.as_os_str().as_bytes());
+            index_info.push(b'\0');
+        }
+
+        if !index_info.is_empty() {
+            let update_index = ["update-index", "-z", "--index-info"];
+            git(&self.root, &update_index, Some(&index_info))?;
+        }
+        if !removed.is_empty() {
+            let remove = ["update-index", "-z", "--force-remove", "--stdin"];
+            git(&self.root, &remove, Some(&removed))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for GitDiff {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// `--path=<path>`, which applies the attributes of `path` to a blob.
+fn path_arg(path: &Path) -> OsString {
+    let mut arg = OsString::from("--path=");
+    arg.push(path);
+    arg
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode, 8).with_context(|| format!("malformed mode '{mode}'"))
+}
+
+/// Runs git in `dir` and returns its output, or its error message if it fails.
+fn git(dir: &Path, args: &[impl AsRef<OsStr>], stdin: Option<&[u8]>) -> Result<Vec<u8>> {
+    let name = args.first().map(|arg| arg.as_ref().to_string_lossy());
+    let name = name.unwrap_or_default();
+    let mut child = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .stdin(match stdin {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("could not run git")?;
+    if let (Some(input), Some(mut child_stdin)) = (stdin, child.stdin.take()) {
+        child_stdin.write_all(input)?;
+    }
+    let output = child.wait_with_output()?;
+    ensure!(
+        output.status.success(),
+        "git {name} failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(output.stdout)
+}
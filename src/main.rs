@@ -1,36 +1,129 @@
+use args::Args;
 use changes::Changes;
 use color_eyre::eyre::{ContextCompat, Result, ensure};
-use config::Options;
-use std::path::PathBuf;
+use config::{Mode, Options};
+use git::{GitCommand, GitDiff};
 use std::process::ExitCode;
 
+mod apply;
+mod args;
 mod changes;
 mod config;
 mod count_lines;
 mod diff_patch;
+mod editor;
+mod git;
+mod output_patch;
+mod patch_file;
+mod renames;
+mod terminal;
 
 use diff_patch::DiffPatch;
 
 fn main() -> Result<ExitCode> {
     color_eyre::install()?;
 
-    let mut args = std::env::args().skip(1);
-    let original_dir = PathBuf::from(args.next().context("missing left path")?);
-    let modified_dir = PathBuf::from(args.next().context("missing right path")?);
-    ensure!(args.count() == 0, "more args than expected");
+    let mut args = Args::parse(std::env::args().skip(1))?;
+    if args.help {
+        print!("{}", args::USAGE);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if args.version {
+        println!("diffpatch {}", env!("CARGO_PKG_VERSION"));
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let cwd = std::env::current_dir()?;
+    let mut paths = std::mem::take(&mut args.paths).into_iter().peekable();
+    let git_command = match paths.next_if(|path| path.as_os_str() == "git") {
+        Some(_) => {
+            let command = paths.next().context("missing git command")?;
+            Some(GitCommand::parse(&command.to_string_lossy(), args.staged)?)
+        }
+        None => None,
+    };
+    ensure!(
+        git_command.is_some() || !args.staged,
+        "--staged only works with git restore"
+    );
 
     let mut options = Options::default();
-    options.jj_subcommand = get_jj_subcommand().unwrap_or(None);
-    options.reversed = options.jj_subcommand.as_deref() == Some("restore");
+    options.load_config_files(&cwd)?;
     options.load_env()?;
+    args.apply(&mut options);
+    if let Some(command) = git_command {
+        options.mode = Some(Mode::Git(command));
+    }
+    if options.mode.is_none() {
+        options.mode = guess_jj_subcommand().unwrap_or(None);
+    }
+    // the mode only decides the direction if it wasn't given on the command line
+    options.reversed = args
+        .reversed
+        .unwrap_or(options.mode.is_some_and(Mode::is_reversed));
+
+    let patch = args.patch.take();
+    let git_diff = match git_command {
+        Some(command) => {
+            ensure!(
+                patch.is_none(),
+                "--patch cannot be combined with git commands"
+            );
+            // like for git, the paths are relative to the current dir
+            let pathspecs: Vec<_> = paths.by_ref().chain(args.filters.drain(..)).collect();
+            Some(GitDiff::prepare(command, &cwd, &pathspecs)?)
+        }
+        None => None,
+    };
+    let (original_dir, modified_dir, output_dir) = match (&git_diff, &patch) {
+        (Some(git_diff), _) => (
+            git_diff.original_dir(),
+            git_diff.modified_dir(),
+            git_diff.output_dir(),
+        ),
+        (None, Some(_)) => {
+            let target_dir = paths.next().context("missing target dir")?;
+            (target_dir.clone(), target_dir.clone(), target_dir)
+        }
+        (None, None) => {
+            let original_dir = paths.next().context("missing left path")?;
+            let modified_dir = paths.next().context("missing right path")?;
+            let output_dir = paths.next().unwrap_or_else(|| modified_dir.clone());
+            (original_dir, modified_dir, output_dir)
+        }
+    };
+    ensure!(
+        paths.len() == 0,
+        "more args than expected, see --help for usage"
+    );
+
+    let output_patch = options.output_patch.is_some();
     let mut diff_patch = DiffPatch::new(options)?;
 
-    let changes = Changes::detect(&original_dir, &modified_dir)?;
-    diff_patch.run(&changes)
+    let mut changes = match patch {
+        Some(patch) => {
+            let mut changes = Changes::from_patch(&patch, &modified_dir)?;
+            if !args.filters.is_empty() {
+                changes.retain_paths(&args.filters);
+            }
+            changes
+        }
+        None => Changes::detect(&original_dir, &modified_dir, &output_dir, &args.filters)?,
+    };
+    // git writes over the original side, either its copy or the worktree
+    changes.output_is_original = git_diff.is_some();
+    let exit_code = diff_patch.run(&changes)?;
+
+    if let Some(git_diff) = git_diff.filter(|_| exit_code == ExitCode::SUCCESS && !output_patch) {
+        let _deferred = terminal::defer_signals()?;
+        git_diff.update_index()?;
+    }
+    Ok(exit_code)
 }
 
-// TODO: get this from JJ itself
-fn get_jj_subcommand() -> Result<Option<String>> {
+/// Guesses the mode from the command line of the parent process, for when no `--mode` is given.
+/// This fails for wrapper scripts and aliases, so it is only a fallback.
+fn guess_jj_subcommand() -> Result<Option<Mode>> {
     // SAFETY: no preconditions
     let parent = unsafe { libc::getppid() };
     let cmdline = std::fs::read(format!("/proc/{}/cmdline", parent))?;
@@ -39,17 +132,17 @@ fn get_jj_subcommand() -> Result<Option<String>> {
         return Ok(None);
     };
     let subcommand = split
-        .skip_while(|arg| arg.starts_with(b"-"))
-        .next()
+        .find(|arg| !arg.starts_with(b"-"))
         .map(std::str::from_utf8)
         .transpose()?;
 
-    Ok(subcommand.map(|cmd| {
+    Ok(subcommand.and_then(|cmd| {
         let prefixes = [("ci", "commit"), ("sq", "squash")];
         prefixes
             .iter()
             .find_map(|&(prefix, subcommand)| cmd.starts_with(prefix).then_some(subcommand))
             .unwrap_or(cmd)
-            .to_string()
+            .parse()
+            .ok()
     }))
 }
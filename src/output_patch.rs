@@ -0,0 +1,195 @@
+use std::io::Write;
+use std::path::Path;
+
+use crate::apply::{Entry, Outcome};
+use crate::changes::{Changes, Content, FileType};
+
+/// Writes the outcome of every change as one git-style patch against the original dir.
+pub fn write_patch(
+    mut w: impl Write,
+    changes: &Changes,
+    outcomes: &[Outcome],
+    context_len: usize,
+) -> std::io::Result<()> {
+    for outcome in outcomes.iter().filter(|outcome| !outcome.is_unchanged()) {
+        // when diffing two files, the change has no path and the file names are shown instead,
+        // which aren't a rename
+        let git_header = !outcome.current.as_os_str().is_empty();
+        let original =
+            (outcome.original.as_ref()).map(|(path, entry)| (changes.original_name(path), entry));
+        let result =
+            (outcome.result.as_ref()).map(|(path, entry)| (changes.modified_name(path), entry));
+
+        match (original, result) {
+            // like git, a file turning into a symlink is a deletion followed by an addition
+            (Some(original), Some(result)) if original.1.file_type != result.1.file_type => {
+                write_file_diff(&mut w, Some(original), None, false, git_header, context_len)?;
+                write_file_diff(&mut w, None, Some(result), false, git_header, context_len)?;
+            }
+            (original, result) => write_file_diff(
+                &mut w,
+                original,
+                result,
+                outcome.copied,
+                git_header,
+                context_len,
+            )?,
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn check_file_diff() {
+    let entry = |content: &str, mode| Entry {
+        file_type: FileType::File,
+        content: content.as_bytes().to_vec(),
+        mode,
+    };
+    let (old, new) = (entry("a\nb\n", 0o100644), entry("a\nc\n", 0o100775));
+    let mut out = Vec::new();
+    let (from, to) = (Path::new("old.txt"), Path::new("new.txt"));
+    write_file_diff(
+        &mut out,
+        Some((from, &old)),
+        Some((to, &new)),
+        false,
+        true,
+        3,
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "diff --git a/old.txt b/new.txt
+old mode 100644
+new mode 100755
+rename from old.txt
+rename to new.txt
+--- a/old.txt
++++ b/new.txt
+@@ -1,2 +1,2 @@
+ a
+-b
++c
+"
+    );
+
+    let mut out = Vec::new();
+    write_file_diff(&mut out, Some((from, &old)), None, false, true, 3).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "diff --git a/old.txt b/old.txt
+deleted file mode 100644
+--- a/old.txt
++++ /dev/null
+@@ -1,2 +0,0 @@
+-a
+-b
+"
+    );
+
+    // two single files are a plain diff between their names
+    let mut out = Vec::new();
+    write_file_diff(
+        &mut out,
+        Some((from, &old)),
+        Some((to, &new)),
+        false,
+        false,
+        3,
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "--- old.txt
++++ new.txt
+@@ -1,2 +1,2 @@
+ a
+-b
++c
+"
+    );
+}
+/// Without `git_header`, only the content is diffed under the plain paths, like `diff -u`.
+fn write_file_diff(
+    mut w: impl Write,
+    original: Option<(&Path, &Entry)>,
+    result: Option<(&Path, &Entry)>,
+    copied: bool,
+    git_header: bool,
+    context_len: usize,
+) -> std::io::Result<()> {
+    let (Some((a_path, _)), Some((b_path, _))) = (original.or(result), result.or(original)) else {
+        return Ok(());
+    };
+    if !git_header {
+        return write_content_diff(w, original, result, ("", ""), context_len);
+    }
+    writeln!(
+        w,
+        "diff --git a/{} b/{}",
+        a_path.display(),
+        b_path.display()
+    )?;
+
+    match (original, result) {
+        (None, Some((_, new))) => writeln!(w, "new file mode {:o}", git_mode(new))?,
+        (Some((_, old)), None) => writeln!(w, "deleted file mode {:o}", git_mode(old))?,
+        (Some((from, old)), Some((to, new))) => {
+            if git_mode(old) != git_mode(new) {
+                writeln!(w, "old mode {:o}", git_mode(old))?;
+                writeln!(w, "new mode {:o}", git_mode(new))?;
+            }
+            if from != to {
+                let kind = if copied { "copy" } else { "rename" };
+                writeln!(w, "{kind} from {}", from.display())?;
+                writeln!(w, "{kind} to {}", to.display())?;
+            }
+        }
+        (None, None) => {}
+    }
+    write_content_diff(w, original, result, ("a/", "b/"), context_len)
+}
+
+fn write_content_diff(
+    mut w: impl Write,
+    original: Option<(&Path, &Entry)>,
+    result: Option<(&Path, &Entry)>,
+    (old_prefix, new_prefix): (&str, &str),
+    context_len: usize,
+) -> std::io::Result<()> {
+    let old_content = original.map_or(&[][..], |(_, entry)| &entry.content);
+    let new_content = result.map_or(&[][..], |(_, entry)| &entry.content);
+    if old_content == new_content {
+        return Ok(());
+    }
+
+    let old_name = original.map_or("/dev/null".to_owned(), |(path, _)| {
+        format!("{old_prefix}{}", path.display())
+    });
+    let new_name = result.map_or("/dev/null".to_owned(), |(path, _)| {
+        format!("{new_prefix}{}", path.display())
+    });
+    match (
+        Content::from_bytes(old_content.to_vec()),
+        Content::from_bytes(new_content.to_vec()),
+    ) {
+        (Content::Text(old), Content::Text(new)) => {
+            let mut diff_options = diffy::DiffOptions::new();
+            diff_options.set_context_len(context_len);
+            diff_options.set_original_filename(old_name);
+            diff_options.set_modified_filename(new_name);
+            write!(w, "{}", diff_options.create_patch(&old, &new))
+        }
+        _ => writeln!(w, "Binary files {old_name} and {new_name} differ"),
+    }
+}
+
+/// The normalized mode git records, which only distinguishes executables and symlinks.
+fn git_mode(entry: &Entry) -> u32 {
+    match entry.file_type {
+        FileType::Symlink => 0o120000,
+        FileType::File if entry.mode & 0o111 != 0 => 0o100755,
+        FileType::File => 0o100644,
+    }
+}
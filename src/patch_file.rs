@@ -0,0 +1,185 @@
+use color_eyre::Result;
+use color_eyre::eyre::{ContextCompat, bail, ensure, eyre};
+use std::path::{Component, Path, PathBuf};
+
+/// The part of a multi-file diff that changes a single file.
+#[derive(Default, Debug, PartialEq)]
+pub struct FilePatch {
+    /// `None` for added files.
+    pub original: Option<PathBuf>,
+    /// `None` for removed files.
+    pub modified: Option<PathBuf>,
+    pub old_mode: Option<u32>,
+    pub new_mode: Option<u32>,
+    pub copied: bool,
+    pub binary: bool,
+    /// The hunks without any headers, to be parsed by diffy.
+    pub hunks: String,
+}
+
+#[test]
+fn check_split_files() {
+    let input = "commit message
+--- a/gone.txt\t2025-01-01 00:00:00
++++ /dev/null
+@@ -1 +0,0 @@
+-gone
+\\ No newline at end of file
+diff --git a/src/lib.rs b/src/lib.rs
+index 3b18e51..a8c5b2d 100644
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1,2 +1,2 @@
+--- not a header
++++ not a header
+ x
+diff --git a/old b/new
+similarity index 100%
+rename from old
+rename to new
+";
+    let files = split_files(input).unwrap();
+    assert_eq!(files.len(), 3);
+    assert_eq!(files[0].original.as_deref(), Some(Path::new("gone.txt")));
+    assert_eq!(files[0].modified, None);
+    assert_eq!(files[1].original.as_deref(), Some(Path::new("src/lib.rs")));
+    assert_eq!(
+        files[1].hunks,
+        "@@ -1,2 +1,2 @@\n--- not a header\n+++ not a header\n x\n"
+    );
+    assert_eq!(files[2].original.as_deref(), Some(Path::new("old")));
+    assert_eq!(files[2].modified.as_deref(), Some(Path::new("new")));
+    assert_eq!(files[2].hunks, "");
+
+    // like git apply, nothing outside of the target can be patched
+    let escaping = "--- /dev/null\n+++ b/../escaped.txt\n@@ -0,0 +1 @@\n+x\n";
+    assert!(split_files(escaping).is_err());
+    let absolute = "diff --git a/x b/y\nrename from x\nrename to /etc/y\n";
+    assert!(split_files(absolute).is_err());
+}
+/// Splits a unified diff into its files. Like `git apply`, the first path component is stripped
+/// and everything between files, like commit messages, is ignored.
+pub fn split_files(input: &str) -> Result<Vec<FilePatch>> {
+    let mut files: Vec<FilePatch> = Vec::new();
+    let mut in_git_header = false;
+
+    let mut lines = input.split_inclusive('\n').peekable();
+    while let Some(line) = lines.next() {
+        if let Some(names) = line.strip_prefix("diff --git ") {
+            let (original, modified) = git_names(names.trim_end())
+                .with_context(|| format!("unsupported header '{}'", line.trim_end()))?;
+            files.push(FilePatch {
+                original: Some(original),
+                modified: Some(modified),
+                ..Default::default()
+            });
+            in_git_header = true;
+        } else if line.starts_with("--- ") && lines.peek().is_some_and(|l| l.starts_with("+++ ")) {
+            // outside of git diffs, a file starts at its `---` line
+            if !in_git_header {
+                files.push(FilePatch::default());
+            }
+            in_git_header = false;
+            let file = files.last_mut().unwrap();
+            file.original = file_name(&line["--- ".len()..]);
+            file.modified = file_name(&lines.next().unwrap()["+++ ".len()..]);
+        } else if line.starts_with("@@ ") {
+            in_git_header = false;
+            let file = files
+                .last_mut()
+                .context("hunk before the first file header")?;
+            let (mut old_len, mut new_len) = hunk_lens(line)
+                .with_context(|| format!("malformed hunk header '{}'", line.trim_end()))?;
+            file.hunks.push_str(line);
+            while old_len > 0 || new_len > 0 || lines.peek().is_some_and(|l| l.starts_with('\\')) {
+                let line = lines.next().context("unexpected end of hunk")?;
+                match line.as_bytes()[0] {
+                    b' ' | b'\n' if old_len > 0 && new_len > 0 => {
+                        (old_len, new_len) = (old_len - 1, new_len - 1)
+                    }
+                    b'-' if old_len > 0 => old_len -= 1,
+                    b'+' if new_len > 0 => new_len -= 1,
+                    b'\\' => {}
+                    _ => bail!("unexpected line in hunk '{}'", line.trim_end()),
+                }
+                file.hunks.push_str(line);
+            }
+        } else if in_git_header {
+            let file = files.last_mut().unwrap();
+            let line = line.trim_end();
+            let parse_mode = |mode: &str| {
+                u32::from_str_radix(mode, 8).map_err(|_| eyre!("malformed mode '{line}'"))
+            };
+            if let Some(mode) = line.strip_prefix("old mode ") {
+                file.old_mode = Some(parse_mode(mode)?);
+            } else if let Some(mode) = line.strip_prefix("new mode ") {
+                file.new_mode = Some(parse_mode(mode)?);
+            } else if let Some(mode) = line.strip_prefix("deleted file mode ") {
+                (file.modified, file.old_mode) = (None, Some(parse_mode(mode)?));
+            } else if let Some(mode) = line.strip_prefix("new file mode ") {
+                (file.original, file.new_mode) = (None, Some(parse_mode(mode)?));
+            } else if let Some(path) = line.strip_prefix("rename from ") {
+                file.original = Some(PathBuf::from(path));
+            } else if let Some(path) = line.strip_prefix("rename to ") {
+                file.modified = Some(PathBuf::from(path));
+            } else if let Some(path) = line.strip_prefix("copy from ") {
+                (file.original, file.copied) = (Some(PathBuf::from(path)), true);
+            } else if let Some(path) = line.strip_prefix("copy to ") {
+                file.modified = Some(PathBuf::from(path));
+            } else if line.starts_with("Binary files ") || line == "GIT binary patch" {
+                file.binary = true;
+            }
+        }
+    }
+
+    for path in files
+        .iter()
+        .flat_map(|file| file.original.iter().chain(&file.modified))
+    {
+        let inside = path.components().all(|c| matches!(c, Component::Normal(_)));
+        ensure!(
+            inside && !path.as_os_str().is_empty(),
+            "'{}' is outside of the target",
+            path.display()
+        );
+    }
+    Ok(files)
+}
+
+/// Parses the `a/X b/Y` of a `diff --git` line.
+fn git_names(names: &str) -> Option<(PathBuf, PathBuf)> {
+    let (original, modified) = names.strip_prefix("a/")?.split_once(" b/")?;
+    Some((PathBuf::from(original), PathBuf::from(modified)))
+}
+
+/// Parses the name after `---` or `+++`, which is `None` for `/dev/null`.
+fn file_name(name: &str) -> Option<PathBuf> {
+    // timestamps from `diff -u` are separated by a tab
+    let name = name
+        .trim_end_matches('\n')
+        .split('\t')
+        .next()
+        .unwrap_or_default();
+    if name == "/dev/null" {
+        return None;
+    }
+    let mut components = Path::new(name).components();
+    match components.next().is_some() && components.as_path() != Path::new("") {
+        true => Some(components.as_path().to_owned()),
+        false => Some(PathBuf::from(name)),
+    }
+}
+
+/// The number of original and modified lines announced by a `@@ -1,2 +1,3 @@` header.
+fn hunk_lens(header: &str) -> Option<(usize, usize)> {
+    let mut ranges = header
+        .strip_prefix("@@ -")?
+        .split(" @@")
+        .next()?
+        .split(" +");
+    let len = |range: &str| match range.split_once(',') {
+        Some((_, len)) => len.parse().ok(),
+        None => range.parse::<usize>().ok().map(|_| 1),
+    };
+    Some((len(ranges.next()?)?, len(ranges.next()?)?))
+}
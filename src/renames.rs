@@ -0,0 +1,158 @@
+use color_eyre::Result;
+use color_eyre::eyre::Context;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::changes::{ChangeKind, Changes};
+
+/// Minimum similarity in percent for a pair to count as a rename or copy, like git's `-M50%`.
+const SIMILARITY_THRESHOLD: usize = 50;
+/// Above this many compared pairs only exact matches are detected, like git's `diff.renameLimit`.
+const SIMILARITY_LIMIT: usize = 1000 * 1000;
+
+struct Candidate<'a> {
+    index: usize,
+    path: &'a Path,
+    content: Vec<u8>,
+}
+
+/// Pairs up removed and added files into renames, and added files that resemble the original
+/// side of a modified file into copies.
+pub fn detect_renames(changes: &mut Changes) -> Result<()> {
+    let any_added = changes.iter().any(|c| matches!(c, ChangeKind::Added(_)));
+    if !any_added {
+        return Ok(());
+    }
+
+    let (renames, copies) = {
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        let mut modified = Vec::new();
+        for (index, change) in changes.iter().enumerate() {
+            match change {
+                ChangeKind::Removed(path) => {
+                    removed.extend(read_candidate(index, path, changes.original_path(path))?)
+                }
+                ChangeKind::Added(path) => {
+                    added.extend(read_candidate(index, path, changes.modified_path(path))?)
+                }
+                ChangeKind::Modified(path) => {
+                    modified.extend(read_candidate(index, path, changes.original_path(path))?)
+                }
+                _ => {}
+            }
+        }
+
+        let renames = pair_similar(&removed, &added, false);
+        let renamed: HashSet<usize> = renames.iter().map(|&(_, target)| target).collect();
+        added.retain(|candidate| !renamed.contains(&candidate.index));
+        let copies = pair_similar(&modified, &added, true);
+
+        (renames, copies)
+    };
+
+    let renames: HashMap<usize, usize> = renames.into_iter().collect();
+    let renamed: HashSet<usize> = renames.values().copied().collect();
+    let copies: HashMap<usize, usize> = copies.into_iter().map(|(s, t)| (t, s)).collect();
+
+    let paths: Vec<_> = changes.iter().map(|c| c.inner().to_owned()).collect();
+    let old_changes = std::mem::take(&mut changes.changes);
+    changes.changes = old_changes
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !renamed.contains(index))
+        .map(|(index, change)| {
+            if let Some(&target) = renames.get(&index) {
+                ChangeKind::Renamed(paths[index].clone(), paths[target].clone())
+            } else if let Some(&source) = copies.get(&index) {
+                ChangeKind::Copied(paths[source].clone(), paths[index].clone())
+            } else {
+                change
+            }
+        })
+        .collect();
+
+    Ok(())
+}
+
+/// Reads a file to be paired up. Empty files are skipped, just like in git.
+fn read_candidate(index: usize, path: &Path, full_path: PathBuf) -> Result<Option<Candidate<'_>>> {
+    let content = std::fs::read(&full_path)
+        .with_context(|| format!("failed to read '{}'", full_path.display()))?;
+    Ok((!content.is_empty()).then_some(Candidate {
+        index,
+        path,
+        content,
+    }))
+}
+
+/// Pairs each target with its most similar source, best matches first.
+/// Sources are used at most once, unless `reuse_sources` is set.
+fn pair_similar(
+    sources: &[Candidate],
+    targets: &[Candidate],
+    reuse_sources: bool,
+) -> Vec<(usize, usize)> {
+    let exact_only = sources.len() * targets.len() > SIMILARITY_LIMIT;
+
+    let mut scored = Vec::new();
+    for source in sources {
+        for target in targets {
+            let similarity = match source.content == target.content {
+                true => 100,
+                false if exact_only => continue,
+                false => similarity(&source.content, &target.content),
+            };
+            if similarity < SIMILARITY_THRESHOLD {
+                continue;
+            }
+            let same_name = source.path.file_name() == target.path.file_name();
+            scored.push((similarity, same_name, source.index, target.index));
+        }
+    }
+    scored.sort_by_key(|&(similarity, same_name, ..)| Reverse((similarity, same_name)));
+
+    let mut used_sources = HashSet::new();
+    let mut used_targets = HashSet::new();
+    let mut pairs = Vec::new();
+    for (_, _, source, target) in scored {
+        if used_targets.contains(&target) || !reuse_sources && used_sources.contains(&source) {
+            continue;
+        }
+        used_sources.insert(source);
+        used_targets.insert(target);
+        pairs.push((source, target));
+    }
+    pairs
+}
+
+#[test]
+fn check_similarity() {
+    assert_eq!(similarity(b"a\nb\n", b"a\nb\n"), 100);
+    assert_eq!(similarity(b"a\nb\n", b"c\nd\n"), 0);
+    assert_eq!(similarity(b"a\nb\nc\nd\n", b"a\nb\nc\ne\n"), 75);
+    assert_eq!(similarity(b"a\nb\n", b"a\nb\nc\nd\n"), 50);
+}
+/// How much of `modified` consists of lines from `original`, in percent of the larger file.
+fn similarity(original: &[u8], modified: &[u8]) -> usize {
+    let max_len = original.len().max(modified.len());
+    if original.len().min(modified.len()) * 100 < max_len * SIMILARITY_THRESHOLD {
+        return 0;
+    }
+
+    let mut lines: HashMap<&[u8], usize> = HashMap::new();
+    for line in original.split_inclusive(|&b| b == b'\n') {
+        *lines.entry(line).or_default() += 1;
+    }
+
+    let mut common = 0;
+    for line in modified.split_inclusive(|&b| b == b'\n') {
+        if let Some(count) = lines.get_mut(line).filter(|count| **count > 0) {
+            *count -= 1;
+            common += line.len();
+        }
+    }
+
+    common * 100 / max_len
+}
@@ -0,0 +1,123 @@
+use std::mem::MaybeUninit;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The terminal settings from before entering raw mode.
+static ORIGINAL_TERMIOS: OnceLock<libc::termios> = OnceLock::new();
+static ALTERNATE_SCREEN: AtomicBool = AtomicBool::new(false);
+/// Set when everything drawn so far is stale, e.g. after the terminal was resized.
+static REDRAW: AtomicBool = AtomicBool::new(false);
+
+/// Like `termion::screen::ToMainScreen`, but usable from a signal handler.
+const TO_MAIN_SCREEN: &[u8] = b"\x1b[?1049l";
+
+/// Makes sure the terminal is put back into its original state when diffpatch panics or is
+/// killed by SIGTERM, SIGHUP or SIGINT. Must be called before entering raw mode.
+pub fn install_restore_handlers(alternate_screen: bool) -> std::io::Result<()> {
+    let mut termios = MaybeUninit::uninit();
+    // SAFETY: `termios` is initialized by `tcgetattr` if it succeeds
+    if unsafe { libc::tcgetattr(libc::STDOUT_FILENO, termios.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: checked above
+    let _ = ORIGINAL_TERMIOS.set(unsafe { termios.assume_init() });
+    ALTERNATE_SCREEN.store(alternate_screen, Ordering::Relaxed);
+
+    let previous_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore();
+        previous_hook(info);
+        // unwinding would leave the alternate screen again, moving the cursor above the message
+        std::process::exit(101);
+    }));
+
+    for signal in [libc::SIGTERM, libc::SIGHUP, libc::SIGINT] {
+        let handler = handle_signal as extern "C" fn(libc::c_int);
+        // SAFETY: the handler only calls async-signal-safe functions
+        if unsafe { libc::signal(signal, handler as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Requests a redraw whenever the terminal is resized. Blocking reads from the terminal are
+/// interrupted by resizes, so that they can be handled right away.
+pub fn install_resize_handler() -> std::io::Result<()> {
+    // SAFETY: all-zero is a valid `sigaction`, with no flags and an empty mask
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = handle_resize as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic
+    if unsafe { libc::sigaction(libc::SIGWINCH, &action, std::ptr::null_mut()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn request_redraw() {
+    REDRAW.store(true, Ordering::Relaxed);
+}
+pub fn needs_redraw() -> bool {
+    REDRAW.load(Ordering::Relaxed)
+}
+pub fn take_redraw() -> bool {
+    REDRAW.swap(false, Ordering::Relaxed)
+}
+
+/// Holds back SIGTERM, SIGHUP and SIGINT until it is dropped, so that writing the changes can't
+/// be interrupted halfway. Signals received in the meantime are delivered when it is dropped.
+pub struct DeferSignals(libc::sigset_t);
+
+pub fn defer_signals() -> std::io::Result<DeferSignals> {
+    let (mut blocked, mut previous) = (MaybeUninit::uninit(), MaybeUninit::uninit());
+    // SAFETY: `blocked` is initialized by `sigemptyset` before it is used, and `previous` by
+    // `pthread_sigmask` if it succeeds
+    unsafe {
+        libc::sigemptyset(blocked.as_mut_ptr());
+        for signal in [libc::SIGTERM, libc::SIGHUP, libc::SIGINT] {
+            libc::sigaddset(blocked.as_mut_ptr(), signal);
+        }
+        let result =
+            libc::pthread_sigmask(libc::SIG_BLOCK, blocked.as_ptr(), previous.as_mut_ptr());
+        if result != 0 {
+            return Err(std::io::Error::from_raw_os_error(result));
+        }
+        Ok(DeferSignals(previous.assume_init()))
+    }
+}
+
+impl Drop for DeferSignals {
+    fn drop(&mut self) {
+        // SAFETY: restores the mask read by `defer_signals`
+        unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &self.0, std::ptr::null_mut()) };
+    }
+}
+
+extern "C" fn handle_resize(_: libc::c_int) {
+    request_redraw();
+}
+
+extern "C" fn handle_signal(signal: libc::c_int) {
+    restore();
+    // SAFETY: exits without running any non async-signal-safe cleanup
+    unsafe { libc::_exit(128 + signal) };
+}
+
+/// Leaves raw mode and the alternate screen. Only uses async-signal-safe functions.
+fn restore() {
+    if let Some(termios) = ORIGINAL_TERMIOS.get() {
+        // SAFETY: `termios` is a valid configuration read by `tcgetattr`
+        unsafe { libc::tcsetattr(libc::STDOUT_FILENO, libc::TCSANOW, termios) };
+    }
+    if ALTERNATE_SCREEN.load(Ordering::Relaxed) {
+        // SAFETY: writes a static buffer of the given length
+        unsafe {
+            libc::write(
+                libc::STDOUT_FILENO,
+                TO_MAIN_SCREEN.as_ptr().cast(),
+                TO_MAIN_SCREEN.len(),
+            )
+        };
+    }
+}